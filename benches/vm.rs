@@ -0,0 +1,81 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use rlox::{
+    compile::Compiler,
+    obj::Heap,
+    vm::{InterpretResult, Vm},
+};
+
+const FIB: &str = "
+fun fib(n) {
+  if (n < 2) return n;
+  return fib(n - 2) + fib(n - 1);
+}
+fib(22);
+";
+
+const LOOP: &str = "
+var sum = 0;
+for (var i = 0; i < 200000; i = i + 1) {
+  sum = sum + i;
+}
+";
+
+const METHODS: &str = "
+class Counter {
+  init() { this.count = 0; }
+  add(n) { this.count = this.count + n; return this; }
+}
+var counter = Counter();
+for (var i = 0; i < 50000; i = i + 1) {
+  counter.add(i).add(1);
+}
+";
+
+const GLOBALS: &str = "
+var a = 0;
+var b = 1;
+var n = 0;
+while (n < 100000) {
+  var t = a + b;
+  a = b;
+  b = t - a;
+  n = n + 1;
+}
+";
+
+fn run(source: &str) {
+    let mut vm = Vm::init();
+    assert!(matches!(
+        vm.interpret(source.to_string()),
+        InterpretResult::Ok
+    ));
+}
+
+/// A script with many small functions, to measure compiling large inputs.
+fn large_script() -> String {
+    (0..2000)
+        .map(|i| {
+            format!("fun f{i}(a, b) {{ var c = a + b * {i}; if (c > 10) return c; return a; }}\n")
+        })
+        .collect()
+}
+
+fn execution(c: &mut Criterion) {
+    c.bench_function("fib", |b| b.iter(|| run(FIB)));
+    c.bench_function("loop", |b| b.iter(|| run(LOOP)));
+    c.bench_function("methods", |b| b.iter(|| run(METHODS)));
+    c.bench_function("globals", |b| b.iter(|| run(GLOBALS)));
+}
+
+fn compilation(c: &mut Criterion) {
+    let source = large_script();
+    c.bench_function("compile large", |b| {
+        b.iter(|| {
+            let mut heap = Heap::new();
+            Compiler::compile(source.clone(), &mut heap).unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, execution, compilation);
+criterion_main!(benches);
@@ -1,6 +1,103 @@
-use crate::{rle::RunLengthEncoded, value::Value};
+use crate::{rle::RunLengthEncoded, scan::Span, value::Value};
+use std::cell::Cell;
 
-#[derive(Clone, Copy)]
+/// Largest constant index an instruction can refer to, using a long variant.
+pub const MAX_CONSTANTS: usize = 1 << 24;
+
+/// Deepest that function constants may nest inside one another in a chunk
+/// loaded from outside the compiler.
+pub const MAX_FUNCTION_DEPTH: usize = 256;
+
+macro_rules! opcodes {
+    ($($name:ident),* $(,)?) => {
+        /// The first byte of every instruction in [`Chunk::code`].
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        #[repr(u8)]
+        pub enum OpCode {
+            $($name),*
+        }
+
+        impl OpCode {
+            const ALL: &'static [OpCode] = &[$(OpCode::$name),*];
+
+            pub fn from_byte(byte: u8) -> Option<Self> {
+                Self::ALL.get(byte as usize).copied()
+            }
+        }
+    };
+}
+
+// Instructions that refer to a constant come in pairs: the short form has a
+// one-byte index and the `Long` form a three-byte one. New opcodes go at the
+// end, so bytecode files written before they existed still load.
+opcodes! {
+    Const,
+    ConstLong,
+    DefineGlobal,
+    DefineGlobalLong,
+    GetGlobal,
+    GetGlobalLong,
+    SetGlobal,
+    SetGlobalLong,
+    GetLocal,
+    SetLocal,
+    GetUpvalue,
+    SetUpvalue,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    Closure,
+    ClosureLong,
+    CloseUpvalue,
+    Class,
+    ClassLong,
+    GetProperty,
+    GetPropertyLong,
+    SetProperty,
+    SetPropertyLong,
+    Method,
+    MethodLong,
+    Invoke,
+    InvokeLong,
+    Inherit,
+    GetSuper,
+    GetSuperLong,
+    SuperInvoke,
+    SuperInvokeLong,
+    Nil,
+    True,
+    False,
+    Not,
+    Negate,
+    Equal,
+    Greater,
+    Less,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Print,
+    Pop,
+    Return,
+    // Fused instructions, only produced by `Chunk::optimize`.
+    NotEqual,
+    GreaterEqual,
+    LessEqual,
+    AddConst,
+    AddConstLong,
+    IncrementLocal,
+    JumpIfFalsePop,
+    // Lists and maps.
+    BuildList,
+    BuildMap,
+    IndexGet,
+    IndexSet,
+}
+
+/// A decoded instruction. Constant indices, local slots and jump distances are
+/// encoded in [`Chunk::code`] in as few bytes as they fit in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Op {
     Const(usize),
     DefineGlobal(usize),
@@ -8,7 +105,22 @@ pub enum Op {
     SetGlobal(usize),
     GetLocal(usize),
     SetLocal(usize),
+    GetUpvalue(usize),
+    SetUpvalue(usize),
+    Jump(usize),
     JumpIfFalse(usize),
+    Loop(usize),
+    Call(usize),
+    Closure(usize),
+    CloseUpvalue,
+    Class(usize),
+    GetProperty(usize),
+    SetProperty(usize),
+    Method(usize),
+    Invoke(usize, usize),
+    Inherit,
+    GetSuper(usize),
+    SuperInvoke(usize, usize),
     Nil,
     True,
     False,
@@ -24,12 +136,202 @@ pub enum Op {
     Print,
     Pop,
     Return,
+    /// Collects the top `n` values into a new list.
+    BuildList(usize),
+    /// Collects the top `n` key and value pairs into a new map.
+    BuildMap(usize),
+    /// Pops an index or key and the list or map below it, pushing the item there.
+    IndexGet,
+    /// Pops a value, an index or key and a list or map, storing the value and
+    /// pushing it back.
+    IndexSet,
+    NotEqual,
+    GreaterEqual,
+    LessEqual,
+    /// Adds a constant to the value on top of the stack.
+    AddConst(usize),
+    /// Adds one to a local, leaving the result on the stack.
+    IncrementLocal(usize),
+    /// Pops the condition, then jumps if it was falsey.
+    JumpIfFalsePop(usize),
+}
+
+impl Op {
+    /// Appends the encoded instruction to `out`.
+    ///
+    /// The compiler keeps operands within their encodings' limits. Code that
+    /// already failed to compile may exceed them, so they're clamped rather
+    /// than panicking.
+    pub fn encode(self, out: &mut Vec<u8>) {
+        let byte = |operand: usize| u8::try_from(operand).unwrap_or(u8::MAX);
+        let short = |operand: usize| u16::try_from(operand).unwrap_or(u16::MAX).to_le_bytes();
+        let constant = |out: &mut Vec<u8>, code: OpCode, long: OpCode, index: usize| {
+            if let Ok(index) = u8::try_from(index) {
+                out.extend([code as u8, index]);
+            } else {
+                let index = index.min(MAX_CONSTANTS - 1) as u32;
+                out.push(long as u8);
+                out.extend_from_slice(&index.to_le_bytes()[..3]);
+            }
+        };
+        match self {
+            Op::Const(idx) => constant(out, OpCode::Const, OpCode::ConstLong, idx),
+            Op::AddConst(idx) => constant(out, OpCode::AddConst, OpCode::AddConstLong, idx),
+            Op::DefineGlobal(idx) => {
+                constant(out, OpCode::DefineGlobal, OpCode::DefineGlobalLong, idx)
+            }
+            Op::GetGlobal(idx) => constant(out, OpCode::GetGlobal, OpCode::GetGlobalLong, idx),
+            Op::SetGlobal(idx) => constant(out, OpCode::SetGlobal, OpCode::SetGlobalLong, idx),
+            Op::Closure(idx) => constant(out, OpCode::Closure, OpCode::ClosureLong, idx),
+            Op::Class(idx) => constant(out, OpCode::Class, OpCode::ClassLong, idx),
+            Op::GetProperty(idx) => {
+                constant(out, OpCode::GetProperty, OpCode::GetPropertyLong, idx)
+            }
+            Op::SetProperty(idx) => {
+                constant(out, OpCode::SetProperty, OpCode::SetPropertyLong, idx)
+            }
+            Op::Method(idx) => constant(out, OpCode::Method, OpCode::MethodLong, idx),
+            Op::GetSuper(idx) => constant(out, OpCode::GetSuper, OpCode::GetSuperLong, idx),
+            Op::Invoke(idx, arg_count) => {
+                constant(out, OpCode::Invoke, OpCode::InvokeLong, idx);
+                out.push(byte(arg_count));
+            }
+            Op::SuperInvoke(idx, arg_count) => {
+                constant(out, OpCode::SuperInvoke, OpCode::SuperInvokeLong, idx);
+                out.push(byte(arg_count));
+            }
+            Op::GetLocal(slot) => out.extend([OpCode::GetLocal as u8, byte(slot)]),
+            Op::SetLocal(slot) => out.extend([OpCode::SetLocal as u8, byte(slot)]),
+            Op::IncrementLocal(slot) => out.extend([OpCode::IncrementLocal as u8, byte(slot)]),
+            Op::GetUpvalue(idx) => out.extend([OpCode::GetUpvalue as u8, byte(idx)]),
+            Op::SetUpvalue(idx) => out.extend([OpCode::SetUpvalue as u8, byte(idx)]),
+            Op::Call(arg_count) => out.extend([OpCode::Call as u8, byte(arg_count)]),
+            Op::BuildList(count) => out.extend([OpCode::BuildList as u8, byte(count)]),
+            Op::BuildMap(count) => out.extend([OpCode::BuildMap as u8, byte(count)]),
+            Op::Jump(distance) => {
+                out.push(OpCode::Jump as u8);
+                out.extend(short(distance));
+            }
+            Op::JumpIfFalse(distance) => {
+                out.push(OpCode::JumpIfFalse as u8);
+                out.extend(short(distance));
+            }
+            Op::Loop(distance) => {
+                out.push(OpCode::Loop as u8);
+                out.extend(short(distance));
+            }
+            Op::JumpIfFalsePop(distance) => {
+                out.push(OpCode::JumpIfFalsePop as u8);
+                out.extend(short(distance));
+            }
+            Op::CloseUpvalue => out.push(OpCode::CloseUpvalue as u8),
+            Op::Inherit => out.push(OpCode::Inherit as u8),
+            Op::Nil => out.push(OpCode::Nil as u8),
+            Op::True => out.push(OpCode::True as u8),
+            Op::False => out.push(OpCode::False as u8),
+            Op::Not => out.push(OpCode::Not as u8),
+            Op::Negate => out.push(OpCode::Negate as u8),
+            Op::Equal => out.push(OpCode::Equal as u8),
+            Op::Greater => out.push(OpCode::Greater as u8),
+            Op::Less => out.push(OpCode::Less as u8),
+            Op::Add => out.push(OpCode::Add as u8),
+            Op::Subtract => out.push(OpCode::Subtract as u8),
+            Op::Multiply => out.push(OpCode::Multiply as u8),
+            Op::Divide => out.push(OpCode::Divide as u8),
+            Op::Print => out.push(OpCode::Print as u8),
+            Op::Pop => out.push(OpCode::Pop as u8),
+            Op::Return => out.push(OpCode::Return as u8),
+            Op::IndexGet => out.push(OpCode::IndexGet as u8),
+            Op::IndexSet => out.push(OpCode::IndexSet as u8),
+            Op::NotEqual => out.push(OpCode::NotEqual as u8),
+            Op::GreaterEqual => out.push(OpCode::GreaterEqual as u8),
+            Op::LessEqual => out.push(OpCode::LessEqual as u8),
+        }
+    }
+    /// Decodes the instruction starting at `offset`, returning it with its
+    /// length in bytes, or `None` if the bytes there aren't a valid instruction.
+    #[inline]
+    pub fn decode(code: &[u8], offset: usize) -> Option<(Op, usize)> {
+        let byte = |at: usize| code.get(offset + at).map(|b| *b as usize);
+        let short = |at: usize| Some(byte(at)? | byte(at + 1)? << 8);
+        let long = |at: usize| Some(byte(at)? | byte(at + 1)? << 8 | byte(at + 2)? << 16);
+        let op = match OpCode::from_byte(*code.get(offset)?)? {
+            OpCode::Const => (Op::Const(byte(1)?), 2),
+            OpCode::ConstLong => (Op::Const(long(1)?), 4),
+            OpCode::DefineGlobal => (Op::DefineGlobal(byte(1)?), 2),
+            OpCode::DefineGlobalLong => (Op::DefineGlobal(long(1)?), 4),
+            OpCode::GetGlobal => (Op::GetGlobal(byte(1)?), 2),
+            OpCode::GetGlobalLong => (Op::GetGlobal(long(1)?), 4),
+            OpCode::SetGlobal => (Op::SetGlobal(byte(1)?), 2),
+            OpCode::SetGlobalLong => (Op::SetGlobal(long(1)?), 4),
+            OpCode::GetLocal => (Op::GetLocal(byte(1)?), 2),
+            OpCode::SetLocal => (Op::SetLocal(byte(1)?), 2),
+            OpCode::GetUpvalue => (Op::GetUpvalue(byte(1)?), 2),
+            OpCode::SetUpvalue => (Op::SetUpvalue(byte(1)?), 2),
+            OpCode::Jump => (Op::Jump(short(1)?), 3),
+            OpCode::JumpIfFalse => (Op::JumpIfFalse(short(1)?), 3),
+            OpCode::Loop => (Op::Loop(short(1)?), 3),
+            OpCode::Call => (Op::Call(byte(1)?), 2),
+            OpCode::Closure => (Op::Closure(byte(1)?), 2),
+            OpCode::ClosureLong => (Op::Closure(long(1)?), 4),
+            OpCode::CloseUpvalue => (Op::CloseUpvalue, 1),
+            OpCode::Class => (Op::Class(byte(1)?), 2),
+            OpCode::ClassLong => (Op::Class(long(1)?), 4),
+            OpCode::GetProperty => (Op::GetProperty(byte(1)?), 2),
+            OpCode::GetPropertyLong => (Op::GetProperty(long(1)?), 4),
+            OpCode::SetProperty => (Op::SetProperty(byte(1)?), 2),
+            OpCode::SetPropertyLong => (Op::SetProperty(long(1)?), 4),
+            OpCode::Method => (Op::Method(byte(1)?), 2),
+            OpCode::MethodLong => (Op::Method(long(1)?), 4),
+            OpCode::Invoke => (Op::Invoke(byte(1)?, byte(2)?), 3),
+            OpCode::InvokeLong => (Op::Invoke(long(1)?, byte(4)?), 5),
+            OpCode::Inherit => (Op::Inherit, 1),
+            OpCode::GetSuper => (Op::GetSuper(byte(1)?), 2),
+            OpCode::GetSuperLong => (Op::GetSuper(long(1)?), 4),
+            OpCode::SuperInvoke => (Op::SuperInvoke(byte(1)?, byte(2)?), 3),
+            OpCode::SuperInvokeLong => (Op::SuperInvoke(long(1)?, byte(4)?), 5),
+            OpCode::Nil => (Op::Nil, 1),
+            OpCode::True => (Op::True, 1),
+            OpCode::False => (Op::False, 1),
+            OpCode::Not => (Op::Not, 1),
+            OpCode::Negate => (Op::Negate, 1),
+            OpCode::Equal => (Op::Equal, 1),
+            OpCode::Greater => (Op::Greater, 1),
+            OpCode::Less => (Op::Less, 1),
+            OpCode::Add => (Op::Add, 1),
+            OpCode::Subtract => (Op::Subtract, 1),
+            OpCode::Multiply => (Op::Multiply, 1),
+            OpCode::Divide => (Op::Divide, 1),
+            OpCode::Print => (Op::Print, 1),
+            OpCode::Pop => (Op::Pop, 1),
+            OpCode::Return => (Op::Return, 1),
+            OpCode::BuildList => (Op::BuildList(byte(1)?), 2),
+            OpCode::BuildMap => (Op::BuildMap(byte(1)?), 2),
+            OpCode::IndexGet => (Op::IndexGet, 1),
+            OpCode::IndexSet => (Op::IndexSet, 1),
+            OpCode::NotEqual => (Op::NotEqual, 1),
+            OpCode::GreaterEqual => (Op::GreaterEqual, 1),
+            OpCode::LessEqual => (Op::LessEqual, 1),
+            OpCode::AddConst => (Op::AddConst(byte(1)?), 2),
+            OpCode::AddConstLong => (Op::AddConst(long(1)?), 4),
+            OpCode::IncrementLocal => (Op::IncrementLocal(byte(1)?), 2),
+            OpCode::JumpIfFalsePop => (Op::JumpIfFalsePop(short(1)?), 3),
+        };
+        Some(op)
+    }
 }
 
 pub struct Chunk {
-    pub code: Vec<Op>,
+    pub code: Vec<u8>,
     pub constants: Vec<Value>,
+    /// Source line of each byte in `code`.
     pub lines: RunLengthEncoded<usize>,
+    /// Source span of the token each byte was compiled from, parallel to `lines`.
+    pub spans: RunLengthEncoded<Span>,
+    /// Inline cache of the VM's global slot for each constant, parallel to
+    /// `constants`. Filled in the first time a global instruction naming that
+    /// constant runs.
+    pub global_slots: Vec<Cell<Option<u32>>>,
 }
 
 impl Chunk {
@@ -38,14 +340,63 @@ impl Chunk {
             code: Vec::new(),
             constants: Vec::new(),
             lines: RunLengthEncoded::new(),
+            spans: RunLengthEncoded::new(),
+            global_slots: Vec::new(),
         }
     }
-    pub fn add_op(&mut self, code: Op, line: usize) {
-        self.lines.push(line);
-        self.code.push(code);
+    pub fn add_op(&mut self, code: Op, line: usize, span: Span) {
+        let start = self.code.len();
+        code.encode(&mut self.code);
+        let len = self.code.len() - start;
+        self.lines.push_run(len, line);
+        self.spans.push_run(len, span);
     }
     pub fn add_const(&mut self, value: Value) -> usize {
         self.constants.push(value);
+        self.global_slots.push(Cell::new(None));
         self.constants.len() - 1
     }
+    /// Drops all code from byte `len` onwards, along with its lines and spans.
+    pub fn truncate(&mut self, len: usize) {
+        self.code.truncate(len);
+        self.lines.truncate(len);
+        self.spans.truncate(len);
+    }
+    /// Iterates over the decoded instructions with their offsets, stopping at
+    /// the first invalid one.
+    pub fn ops(&self) -> impl Iterator<Item = (usize, Op)> + '_ {
+        let mut offset = 0;
+        std::iter::from_fn(move || {
+            let (op, len) = Op::decode(&self.code, offset)?;
+            let start = offset;
+            offset += len;
+            Some((start, op))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let ops = [
+            Op::Const(7),
+            Op::Const(300),
+            Op::Invoke(70_000, 3),
+            Op::GetLocal(255),
+            Op::Jump(513),
+            Op::Loop(65_535),
+            Op::Return,
+        ];
+        let mut chunk = Chunk::init();
+        for op in ops {
+            chunk.add_op(op, 1, Span::default());
+        }
+        let decoded: Vec<Op> = chunk.ops().map(|(_, op)| op).collect();
+        assert_eq!(decoded, ops);
+        assert_eq!(chunk.code.len(), 2 + 4 + 5 + 2 + 3 + 3 + 1);
+        assert_eq!(chunk.lines.len(), chunk.code.len());
+    }
 }
@@ -1,50 +1,76 @@
+use std::{cmp::Ordering, rc::Rc};
+
+use ahash::AHashMap;
+
 use crate::{
-    chunk::{Chunk, Op},
-    scan::{Scanner, Token, TokenKind},
-    value::Value,
+    chunk::{Chunk, Op, MAX_CONSTANTS},
+    diagnostic::{Anchor, Diagnostic, Note, Severity},
+    obj::{Capture, Function, Gc, Heap, Str},
+    scan::{Scanner, Span, Token, TokenKind},
+    value::{Value, ValueKind},
 };
 
+const MAX_ARITY: usize = 255;
+const MAX_LIST_ITEMS: usize = 255;
+const MAX_MAP_ENTRIES: usize = 255;
+const MAX_UPVALUES: usize = 256;
+const MAX_LOCALS: usize = 256;
+/// Encoded size of `Jump`, `JumpIfFalse` and `Loop`: an opcode and a two-byte distance.
+const JUMP_SIZE: usize = 3;
+
 pub struct Compiler {
     scanner: Scanner,
     current: Token,
     previous: Token,
     had_error: bool,
     panic_mode: bool,
-    chunk: Chunk,
-    scope_depth: usize,
-    locals: Vec<Local>,
+    diagnostics: Vec<Diagnostic>,
+    functions: Vec<FunctionCompiler>,
+    classes: Vec<ClassCompiler>,
+    /// Borrowed from the VM for the duration of compilation. Nothing is
+    /// collected while compiling, so constants need no extra rooting.
+    heap: Heap,
 }
 
 impl Compiler {
-    pub fn compile(source: String) -> Result<Chunk, ()> {
+    /// Compiles a script into its top-level function. On failure, returns every
+    /// diagnostic found; the parser resynchronizes after each error so one pass can report several.
+    pub fn compile(source: String, heap: &mut Heap) -> Result<Gc<Function>, Vec<Diagnostic>> {
         let scanner = Scanner::init(source);
-        let chunk = Chunk::init();
         let mut compiler = Compiler {
             scanner,
-            chunk,
             current: Default::default(),
             previous: Default::default(),
             had_error: false,
             panic_mode: false,
-            scope_depth: 0,
-            locals: Vec::new(),
+            diagnostics: Vec::new(),
+            functions: Vec::new(),
+            classes: Vec::new(),
+            heap: std::mem::take(heap),
         };
+        compiler.begin_function(FunctionKind::Script);
         compiler.advance();
         while !compiler.match_t(TokenKind::Eof) {
             compiler.declaration();
         }
-        compiler.end();
+        let function = compiler.end();
+        let function = compiler.heap.alloc(function);
+        *heap = compiler.heap;
         if compiler.had_error {
-            Err(())
+            Err(compiler.diagnostics)
         } else {
-            Ok(compiler.chunk)
+            Ok(function)
         }
     }
     fn expression(&mut self) {
         self.parse_precedence(Precedence::Assignment);
     }
     fn declaration(&mut self) {
-        if self.match_t(TokenKind::Var) {
+        if self.match_t(TokenKind::Class) {
+            self.class_declaration();
+        } else if self.match_t(TokenKind::Fun) {
+            self.fun_declaration();
+        } else if self.match_t(TokenKind::Var) {
             self.var_declaration();
         } else {
             self.statement();
@@ -56,8 +82,14 @@ impl Compiler {
     fn statement(&mut self) {
         if self.match_t(TokenKind::Print) {
             self.print_statement();
+        } else if self.match_t(TokenKind::For) {
+            self.for_statement();
         } else if self.match_t(TokenKind::If) {
             self.if_statement();
+        } else if self.match_t(TokenKind::Return) {
+            self.return_statement();
+        } else if self.match_t(TokenKind::While) {
+            self.while_statement();
         } else if self.match_t(TokenKind::LeftBrace) {
             self.begin_scope();
             self.block();
@@ -66,10 +98,114 @@ impl Compiler {
             self.expression_statement();
         }
     }
+    fn class_declaration(&mut self) {
+        self.consume(TokenKind::Identifier, "Expect class name.");
+        let class_name = self.previous.clone();
+        let name_constant = self.identifier_constant(&class_name);
+        self.declare_variable();
+
+        self.emit(Op::Class(name_constant));
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassCompiler {
+            has_superclass: false,
+        });
+
+        if self.match_t(TokenKind::Less) {
+            self.consume(TokenKind::Identifier, "Expect superclass name.");
+            self.variable(false);
+
+            if class_name.src == self.previous.src {
+                self.error("A class can't inherit from itself.");
+            }
+
+            // Keep the superclass in a local named `super` so methods can capture it.
+            self.begin_scope();
+            self.add_local(synthetic_token("super"));
+            self.define_variable(0);
+
+            self.named_variable(&class_name, false);
+            self.emit(Op::Inherit);
+            if let Some(class) = self.classes.last_mut() {
+                class.has_superclass = true;
+            }
+        }
+
+        // Load the class back onto the stack so methods can be bound to it.
+        self.named_variable(&class_name, false);
+        self.consume(TokenKind::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            self.method();
+        }
+        self.consume(TokenKind::RightBrace, "Expect '}' after class body.");
+        self.emit(Op::Pop);
+
+        if self
+            .classes
+            .last()
+            .is_some_and(|class| class.has_superclass)
+        {
+            self.end_scope();
+        }
+        self.classes.pop();
+    }
+    fn method(&mut self) {
+        self.consume(TokenKind::Identifier, "Expect method name.");
+        let name = self.previous.clone();
+        let constant = self.identifier_constant(&name);
+        let kind = if name.src == "init" {
+            FunctionKind::Initializer
+        } else {
+            FunctionKind::Method
+        };
+        self.function(kind);
+        self.emit(Op::Method(constant));
+    }
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // A function may refer to itself, so it is usable before its body is compiled.
+        self.mark_initialized();
+        self.function(FunctionKind::Function);
+        self.define_variable(global);
+    }
+    fn function(&mut self, kind: FunctionKind) {
+        self.begin_function(kind);
+        self.begin_scope();
+
+        self.consume(TokenKind::LeftParen, "Expect '(' after function name.");
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                self.state().function.arity += 1;
+                if self.state().function.arity > MAX_ARITY {
+                    self.error_at_current(format!("Can't have more than {MAX_ARITY} parameters."));
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+                if !self.match_t(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenKind::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenKind::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        // No end_scope: the frame's slots are discarded wholesale when the function returns.
+        let function = self.end();
+        let function = self.heap.alloc(function);
+        let const_idx = self.make_constant(Value::from(function));
+        self.emit(Op::Closure(const_idx));
+    }
     fn var_declaration(&mut self) {
         let global = self.parse_variable("Expect variable name.");
 
         if self.match_t(TokenKind::Equal) {
+            let initializer = self.current.clone();
+            if self.state().scope_depth > 0 {
+                if let Some(local) = self.state().locals.last_mut() {
+                    local.initializer = Some(initializer);
+                }
+            }
             self.expression();
         } else {
             self.emit(Op::Nil);
@@ -85,10 +221,7 @@ impl Compiler {
         self.consume(TokenKind::Identifier, error);
 
         self.declare_variable();
-        if self.scope_depth > 0 {
-            if let Some(v) = self.locals.last_mut() {
-                v.init = true;
-            }
+        if self.state().scope_depth > 0 {
             return 0;
         };
 
@@ -97,39 +230,146 @@ impl Compiler {
     }
     fn if_statement(&mut self) {
         self.consume(TokenKind::LeftParen, "Expect '(' after 'if'.");
+        let condition_start = self.current_chunk().code.len();
+        self.expression();
+        self.consume(TokenKind::RightParen, "Expect ')' after condition.");
+
+        if let Some(condition) = self.constant_condition(condition_start) {
+            self.truncate_code(condition_start);
+            self.branch(!condition.is_falsey());
+            if self.match_t(TokenKind::Else) {
+                self.branch(condition.is_falsey());
+            }
+            return;
+        }
+
+        let then_jump = self.emit_jump(Op::JumpIfFalse(usize::MAX));
+        self.emit(Op::Pop);
+        self.statement();
+
+        let else_jump = self.emit_jump(Op::Jump(usize::MAX));
+        self.patch_jump(then_jump);
+        self.emit(Op::Pop);
+
+        if self.match_t(TokenKind::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+    /// Compiles a statement, discarding its code unless `live`. Dead branches
+    /// are still compiled so that errors in them are reported.
+    fn branch(&mut self, live: bool) {
+        let start = self.current_chunk().code.len();
+        self.statement();
+        if !live {
+            self.truncate_code(start);
+        }
+    }
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().code.len();
+        self.consume(TokenKind::LeftParen, "Expect '(' after 'while'.");
         self.expression();
         self.consume(TokenKind::RightParen, "Expect ')' after condition.");
 
-        let jump_loc = self.emit_jump(Op::JumpIfFalse(usize::MAX));
+        let exit_jump = self.emit_jump(Op::JumpIfFalse(usize::MAX));
+        self.emit(Op::Pop);
         self.statement();
+        self.emit_loop(loop_start);
 
-        self.chunk.code[jump_loc] = Op::JumpIfFalse(jump_loc);
+        self.patch_jump(exit_jump);
+        self.emit(Op::Pop);
+    }
+    fn for_statement(&mut self) {
+        self.begin_scope();
+        self.consume(TokenKind::LeftParen, "Expect '(' after 'for'.");
+        if self.match_t(TokenKind::Semicolon) {
+            // No initializer.
+        } else if self.match_t(TokenKind::Var) {
+            self.var_declaration();
+        } else {
+            self.clause();
+            self.consume(TokenKind::Semicolon, "Expect ';' after expression.");
+            self.emit(Op::Pop);
+        }
+
+        let mut loop_start = self.current_chunk().code.len();
+        let mut exit_jump = None;
+        if !self.match_t(TokenKind::Semicolon) {
+            self.clause();
+            self.consume(TokenKind::Semicolon, "Expect ';' after loop condition.");
+
+            // Jump out of the loop if the condition is false.
+            exit_jump = Some(self.emit_jump(Op::JumpIfFalse(usize::MAX)));
+            self.emit(Op::Pop);
+        }
+
+        if !self.match_t(TokenKind::RightParen) {
+            let body_jump = self.emit_jump(Op::Jump(usize::MAX));
+            let increment_start = self.current_chunk().code.len();
+            self.clause();
+            self.emit(Op::Pop);
+            self.consume(TokenKind::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit(Op::Pop);
+        }
+        self.end_scope();
+    }
+    /// Compiles one of a `for` loop's clauses. Lox reads a `{` there as the
+    /// start of a block, which a clause can't hold, so unlike other
+    /// expressions a clause can't begin with a map literal.
+    fn clause(&mut self) {
+        if self.check(TokenKind::LeftBrace) {
+            self.advance();
+            self.error("Expect expression.");
+            return;
+        }
+        self.expression();
     }
     fn identifier_constant(&mut self, token: &Token) -> usize {
-        let const_data = token.src.clone().into();
-        self.current_chunk().add_const(Value::Str(const_data))
+        let const_data = self.heap.intern(&token.src);
+        self.make_constant(Value::from(const_data))
     }
     fn define_variable(&mut self, global: usize) {
-        if self.scope_depth > 0 {
+        if self.state().scope_depth > 0 {
+            self.mark_initialized();
             return;
         }
         self.emit(Op::DefineGlobal(global));
     }
+    fn mark_initialized(&mut self) {
+        if self.state().scope_depth == 0 {
+            return;
+        }
+        if let Some(local) = self.state().locals.last_mut() {
+            local.init = true;
+        }
+    }
     fn declare_variable(&mut self) {
-        if self.scope_depth == 0 {
+        let scope_depth = self.state().scope_depth;
+        if scope_depth == 0 {
             return;
         }
         let name = self.previous.clone();
-        for local in self.locals.clone().iter().rev() {
-            if local.depth < self.scope_depth {
+        for local in self.state().locals.clone().iter().rev() {
+            if local.depth < scope_depth {
                 break;
             }
 
             if name.src == local.name.src {
-                self.error(format!(
-                    "Already a variable with the name {} in this scope.",
-                    name.src
-                ));
+                self.error_with_note(
+                    "Already a variable with this name in this scope.",
+                    note(&local.name, "variable declared here"),
+                );
             }
         }
 
@@ -140,6 +380,21 @@ impl Compiler {
         self.consume(TokenKind::Semicolon, "Expect ';' after value.");
         self.emit(Op::Print);
     }
+    fn return_statement(&mut self) {
+        if self.state().kind == FunctionKind::Script {
+            self.error("Can't return from top-level code.");
+        }
+        if self.match_t(TokenKind::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.state().kind == FunctionKind::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+            self.expression();
+            self.consume(TokenKind::Semicolon, "Expect ';' after return value.");
+            self.emit(Op::Return);
+        }
+    }
     fn expression_statement(&mut self) {
         self.expression();
         self.consume(TokenKind::Semicolon, "Expect ';' after expression.");
@@ -152,9 +407,13 @@ impl Compiler {
     fn named_variable(&mut self, name: &Token, can_assign: bool) {
         let get_op;
         let set_op;
-        if let Some(idx) = self.resolve_local(&name) {
+        let current = self.functions.len() - 1;
+        if let Some(idx) = self.resolve_local(current, name) {
             get_op = Op::GetLocal(idx);
             set_op = Op::SetLocal(idx);
+        } else if let Some(idx) = self.resolve_upvalue(current, name) {
+            get_op = Op::GetUpvalue(idx);
+            set_op = Op::SetUpvalue(idx);
         } else {
             let idx = self.identifier_constant(name);
             get_op = Op::GetGlobal(idx);
@@ -167,19 +426,52 @@ impl Compiler {
             self.emit(get_op);
         }
     }
-    fn resolve_local(&mut self, name: &Token) -> Option<usize> {
-        let mut index = self.locals.len();
-        for local in self.locals.iter().rev() {
-            index -= 1;
-            if local.name.src == name.src {
-                if !local.init {
-                    self.error("Can't read local variable in its own initializer.");
+    fn resolve_local(&mut self, function: usize, name: &Token) -> Option<usize> {
+        let (index, local) = self.functions[function]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name.src == name.src)
+            .map(|(index, local)| (index, local.clone()))?;
+        if !local.init {
+            let message = "Can't read local variable in its own initializer.";
+            match &local.initializer {
+                Some(start) => {
+                    self.error_with_note(message, note(start, "initializer started here"))
                 }
-                return Some(index);
+                None => self.error(message),
             }
         }
-
-        return None;
+        Some(index)
+    }
+    /// Resolves `name` as a variable captured from one of the functions
+    /// enclosing `function`, threading the upvalue through every function in between.
+    fn resolve_upvalue(&mut self, function: usize, name: &Token) -> Option<usize> {
+        if function == 0 {
+            return None;
+        }
+        let enclosing = function - 1;
+        if let Some(local) = self.resolve_local(enclosing, name) {
+            self.functions[enclosing].locals[local].captured = true;
+            return Some(self.add_upvalue(function, local, true));
+        }
+        let upvalue = self.resolve_upvalue(enclosing, name)?;
+        Some(self.add_upvalue(function, upvalue, false))
+    }
+    fn add_upvalue(&mut self, function: usize, index: usize, is_local: bool) -> usize {
+        let capture = Capture { index, is_local };
+        let upvalues = &self.functions[function].function.upvalues;
+        if let Some(existing) = upvalues.iter().position(|upvalue| *upvalue == capture) {
+            return existing;
+        }
+        if upvalues.len() == MAX_UPVALUES {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+        let upvalues = &mut self.functions[function].function.upvalues;
+        upvalues.push(capture);
+        upvalues.len() - 1
     }
     fn number(&mut self, _can_assign: bool) {
         let num: f64 = self
@@ -187,18 +479,32 @@ impl Compiler {
             .src
             .parse()
             .expect("Manually validated float unparsable");
-        self.emit_const(Value::Number(num));
+        self.emit_const(Value::from(num));
     }
     fn unary(&mut self, _can_assign: bool) {
-        let operator_kind = self.previous.kind;
+        let operator = self.previous.clone();
 
         // Compile the operand.
         self.parse_precedence(Precedence::Unary);
 
+        if let Some((start, operand)) = self.constant_operand(1) {
+            let result = match (operator.kind, operand.kind()) {
+                (TokenKind::Minus, ValueKind::Number(number)) => Some(Value::from(-number)),
+                (TokenKind::Bang, _) => Some(Value::from(operand.is_falsey())),
+                // Left for the VM to report as a type error.
+                _ => None,
+            };
+            if let Some(result) = result {
+                self.truncate_code(start);
+                self.emit_value(&operator, result);
+                return;
+            }
+        }
+
         // Emit the operator instruction.
-        match operator_kind {
-            TokenKind::Minus => self.emit(Op::Negate),
-            TokenKind::Bang => self.emit(Op::Not),
+        match operator.kind {
+            TokenKind::Minus => self.emit_for(&operator, Op::Negate),
+            TokenKind::Bang => self.emit_for(&operator, Op::Not),
             _ => unreachable!(),
         }
     }
@@ -207,24 +513,197 @@ impl Compiler {
         self.consume(TokenKind::RightParen, "Expect ')' after expression.");
     }
     fn binary(&mut self, _can_assign: bool) {
-        let operator_kind = self.previous.kind;
-        let rule: ParseRule = operator_kind.into();
+        let operator = self.previous.clone();
+        let rule: ParseRule = operator.kind.into();
         self.parse_precedence(rule.precedence.next());
 
-        match operator_kind {
-            TokenKind::Plus => self.emit(Op::Add),
-            TokenKind::Minus => self.emit(Op::Subtract),
-            TokenKind::Star => self.emit(Op::Multiply),
-            TokenKind::Slash => self.emit(Op::Divide),
-            TokenKind::BangEqual => self.emit2(Op::Equal, Op::Not),
-            TokenKind::EqualEqual => self.emit(Op::Equal),
-            TokenKind::Greater => self.emit(Op::Greater),
-            TokenKind::GreaterEqual => self.emit2(Op::Less, Op::Not),
-            TokenKind::Less => self.emit(Op::Less),
-            TokenKind::LessEqual => self.emit2(Op::Greater, Op::Not),
+        if self.fold_binary(&operator) {
+            return;
+        }
+
+        match operator.kind {
+            TokenKind::Plus => self.emit_for(&operator, Op::Add),
+            TokenKind::Minus => self.emit_for(&operator, Op::Subtract),
+            TokenKind::Star => self.emit_for(&operator, Op::Multiply),
+            TokenKind::Slash => self.emit_for(&operator, Op::Divide),
+            TokenKind::BangEqual => self.emit2_for(&operator, Op::Equal, Op::Not),
+            TokenKind::EqualEqual => self.emit_for(&operator, Op::Equal),
+            TokenKind::Greater => self.emit_for(&operator, Op::Greater),
+            TokenKind::GreaterEqual => self.emit2_for(&operator, Op::Less, Op::Not),
+            TokenKind::Less => self.emit_for(&operator, Op::Less),
+            TokenKind::LessEqual => self.emit2_for(&operator, Op::Greater, Op::Not),
             _ => unreachable!(),
         }
     }
+    /// Replaces two constant operands with the result of `operator`, unless
+    /// it would raise a runtime error. Returns whether it did.
+    fn fold_binary(&mut self, operator: &Token) -> bool {
+        let (Some((start, a)), Some((_, b))) = (self.constant_operand(2), self.constant_operand(1))
+        else {
+            return false;
+        };
+        let result = match (operator.kind, a.kind(), b.kind()) {
+            (TokenKind::EqualEqual, _, _) => Value::from(a == b),
+            (TokenKind::BangEqual, _, _) => Value::from(a != b),
+            (TokenKind::Plus, ValueKind::Str(a), ValueKind::Str(b)) => {
+                let joined = format!("{}{}", &**self.heap.get(a), &**self.heap.get(b));
+                Value::from(self.heap.intern(&joined))
+            }
+            (kind, ValueKind::Number(a), ValueKind::Number(b)) => match kind {
+                TokenKind::Plus => Value::from(a + b),
+                TokenKind::Minus => Value::from(a - b),
+                TokenKind::Star => Value::from(a * b),
+                TokenKind::Slash => Value::from(a / b),
+                TokenKind::Greater => Value::from(a > b),
+                // Mirror the `Less, Not` pairs the VM runs, which differ for NaN.
+                TokenKind::GreaterEqual => Value::from(a.partial_cmp(&b) != Some(Ordering::Less)),
+                TokenKind::Less => Value::from(a < b),
+                TokenKind::LessEqual => Value::from(a.partial_cmp(&b) != Some(Ordering::Greater)),
+                _ => unreachable!(),
+            },
+            _ => return false,
+        };
+        self.truncate_code(start);
+        self.emit_value(operator, result);
+        true
+    }
+    fn call(&mut self, _can_assign: bool) {
+        let paren = self.previous.clone();
+        let arg_count = self.argument_list();
+        self.emit_for(&paren, Op::Call(arg_count));
+    }
+    fn argument_list(&mut self) -> usize {
+        let mut arg_count = 0;
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == MAX_ARITY {
+                    self.error(format!("Can't have more than {MAX_ARITY} arguments."));
+                }
+                arg_count += 1;
+                if !self.match_t(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenKind::RightParen, "Expect ')' after arguments.");
+        arg_count
+    }
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenKind::Identifier, "Expect property name after '.'.");
+        let name = self.previous.clone();
+        let name_constant = self.identifier_constant(&name);
+
+        if can_assign && self.match_t(TokenKind::Equal) {
+            self.expression();
+            self.emit(Op::SetProperty(name_constant));
+        } else if self.match_t(TokenKind::LeftParen) {
+            let arg_count = self.argument_list();
+            self.emit_for(&name, Op::Invoke(name_constant, arg_count));
+        } else {
+            self.emit(Op::GetProperty(name_constant));
+        }
+    }
+    fn list(&mut self, _can_assign: bool) {
+        let mut count = 0;
+        while !self.check(TokenKind::RightBracket) {
+            self.expression();
+            if count == MAX_LIST_ITEMS {
+                self.error(format!(
+                    "Can't have more than {MAX_LIST_ITEMS} items in a list literal."
+                ));
+            }
+            count += 1;
+            if !self.match_t(TokenKind::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenKind::RightBracket, "Expect ']' after list items.");
+        self.emit(Op::BuildList(count));
+    }
+    /// Compiles a `{key: value}` literal. A `{` that starts a statement or a
+    /// `for` clause is still a block, as in standard Lox, so a map literal
+    /// can only appear where a block couldn't.
+    fn map(&mut self, _can_assign: bool) {
+        let mut count = 0;
+        while !self.check(TokenKind::RightBrace) {
+            self.expression();
+            self.consume(TokenKind::Colon, "Expect ':' after map key.");
+            self.expression();
+            if count == MAX_MAP_ENTRIES {
+                self.error(format!(
+                    "Can't have more than {MAX_MAP_ENTRIES} entries in a map literal."
+                ));
+            }
+            count += 1;
+            if !self.match_t(TokenKind::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenKind::RightBrace, "Expect '}' after map entries.");
+        self.emit(Op::BuildMap(count));
+    }
+    fn index(&mut self, can_assign: bool) {
+        let bracket = self.previous.clone();
+        self.expression();
+        self.consume(TokenKind::RightBracket, "Expect ']' after index.");
+
+        if can_assign && self.match_t(TokenKind::Equal) {
+            self.expression();
+            self.emit_for(&bracket, Op::IndexSet);
+        } else {
+            self.emit_for(&bracket, Op::IndexGet);
+        }
+    }
+    fn this(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+        self.variable(false);
+    }
+    fn super_(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            }
+            Some(_) => {}
+        }
+
+        self.consume(TokenKind::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenKind::Identifier, "Expect superclass method name.");
+        let name = self.previous.clone();
+        let name_constant = self.identifier_constant(&name);
+
+        self.named_variable(&synthetic_token("this"), false);
+        if self.match_t(TokenKind::LeftParen) {
+            let arg_count = self.argument_list();
+            self.named_variable(&synthetic_token("super"), false);
+            self.emit(Op::SuperInvoke(name_constant, arg_count));
+        } else {
+            self.named_variable(&synthetic_token("super"), false);
+            self.emit(Op::GetSuper(name_constant));
+        }
+    }
+    fn and(&mut self, _can_assign: bool) {
+        let end_jump = self.emit_jump(Op::JumpIfFalse(usize::MAX));
+
+        self.emit(Op::Pop);
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+    fn or(&mut self, _can_assign: bool) {
+        let else_jump = self.emit_jump(Op::JumpIfFalse(usize::MAX));
+        let end_jump = self.emit_jump(Op::Jump(usize::MAX));
+
+        self.patch_jump(else_jump);
+        self.emit(Op::Pop);
+
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
     fn literal(&mut self, _can_assign: bool) {
         match self.previous.kind {
             TokenKind::Nil => self.emit(Op::Nil),
@@ -235,7 +714,8 @@ impl Compiler {
     }
     fn string(&mut self, _can_assign: bool) {
         let last_idx = self.previous.src.len() - 2;
-        self.emit_const(Value::Str(self.previous.src[1..=last_idx].into()));
+        let string = self.heap.intern(&self.previous.src[1..=last_idx]);
+        self.emit_const(Value::from(string));
     }
     fn parse_precedence(&mut self, precedence: Precedence) {
         self.advance();
@@ -253,7 +733,7 @@ impl Compiler {
             infix_rule(self, can_assign);
         }
         if can_assign && self.match_t(TokenKind::Equal) {
-            self.error("Invalid assignment target");
+            self.error("Invalid assignment target.");
         };
     }
     fn scan_token(&mut self) -> Token {
@@ -276,20 +756,32 @@ impl Compiler {
     fn error(&mut self, message: impl std::fmt::Display) {
         self.error_at(self.previous.clone(), message);
     }
+    fn error_with_note(&mut self, message: impl std::fmt::Display, note: Note) {
+        self.report(self.previous.clone(), message, vec![note]);
+    }
     fn error_at(&mut self, token: Token, message: impl std::fmt::Display) {
+        self.report(token, message, Vec::new());
+    }
+    fn report(&mut self, token: Token, message: impl std::fmt::Display, notes: Vec<Note>) {
         if self.panic_mode {
             return;
         }
         self.panic_mode = true;
-        eprint!("[line {}] Error", token.line);
-
-        if token.kind == TokenKind::Eof {
-            eprint!(" at end");
-        } else if token.kind != TokenKind::Error {
-            eprint!(" at '{}'", token.src);
-        }
 
-        eprintln!(": {}\n", message);
+        let anchor = match token.kind {
+            TokenKind::Eof => Anchor::End,
+            TokenKind::Error => Anchor::Bare,
+            _ => Anchor::Lexeme(token.src),
+        };
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            message: message.to_string(),
+            line: token.line,
+            column: token.column,
+            span: token.span,
+            anchor,
+            notes,
+        });
         self.had_error = true;
     }
     fn synchronize(&mut self) {
@@ -325,34 +817,148 @@ impl Compiler {
         self.error_at_current(message);
     }
     fn emit(&mut self, instruction: Op) {
-        let previous_line = self.previous.line;
-        self.current_chunk().add_op(instruction, previous_line);
+        let (line, span) = (self.previous.line, self.previous.span);
+        self.add_op(instruction, line, span);
     }
-    fn emit2(&mut self, i1: Op, i2: Op) {
-        self.emit(i1);
-        self.emit(i2);
+    /// Emits an instruction attributed to `token` rather than the previous
+    /// token, so runtime errors point at the operator instead of its operands.
+    fn emit_for(&mut self, token: &Token, instruction: Op) {
+        self.add_op(instruction, token.line, token.span);
     }
-    fn emit_const(&mut self, value: Value) -> usize {
+    fn add_op(&mut self, instruction: Op, line: usize, span: Span) {
+        let start = self.current_chunk().code.len();
+        self.state().op_starts.push(start);
+        self.current_chunk().add_op(instruction, line, span);
+    }
+    /// Emits the cheapest instruction that pushes `value`.
+    fn emit_value(&mut self, token: &Token, value: Value) {
+        let instruction = match value.kind() {
+            ValueKind::Nil => Op::Nil,
+            ValueKind::Bool(true) => Op::True,
+            ValueKind::Bool(false) => Op::False,
+            _ => Op::Const(self.make_constant(value)),
+        };
+        self.emit_for(token, instruction);
+    }
+    /// The start and value of the instruction `back` places from the end of
+    /// the chunk, if it pushes a constant and folding it away is safe.
+    fn constant_operand(&mut self, back: usize) -> Option<(usize, Value)> {
+        let state = self.state();
+        let index = state.op_starts.len().checked_sub(back)?;
+        let start = state.op_starts[index];
+        // Code a jump lands inside of can't be rewritten.
+        if start < state.jump_target {
+            return None;
+        }
+        let chunk = &state.function.chunk;
+        let value = match Op::decode(&chunk.code, start)?.0 {
+            Op::Const(idx) => chunk.constants[idx],
+            Op::Nil => Value::NIL,
+            Op::True => Value::from(true),
+            Op::False => Value::from(false),
+            _ => return None,
+        };
+        Some((start, value))
+    }
+    /// The value of an `if` condition that compiled to a single constant.
+    fn constant_condition(&mut self, condition_start: usize) -> Option<Value> {
+        let (start, value) = self.constant_operand(1)?;
+        (start == condition_start).then_some(value)
+    }
+    fn truncate_code(&mut self, len: usize) {
+        let state = self.state();
+        state.function.chunk.truncate(len);
+        let kept = state.op_starts.partition_point(|&start| start < len);
+        state.op_starts.truncate(kept);
+        state.jump_target = state.jump_target.min(len);
+    }
+    fn emit2_for(&mut self, token: &Token, i1: Op, i2: Op) {
+        self.emit_for(token, i1);
+        self.emit_for(token, i2);
+    }
+    /// Adds `value` to the current chunk's constants, reusing an existing
+    /// entry for the same number or string.
+    fn make_constant(&mut self, value: Value) -> usize {
+        let key = match value.kind() {
+            ValueKind::Number(number) => Some(ConstantKey::Number(number.to_bits())),
+            ValueKind::Str(string) => Some(ConstantKey::Str(string)),
+            _ => None,
+        };
+        if let Some(const_idx) = key.and_then(|key| self.state().constants.get(&key)) {
+            return *const_idx;
+        }
         let const_idx = self.current_chunk().add_const(value);
+        if const_idx >= MAX_CONSTANTS {
+            self.error("Too many constants in one chunk.");
+            return 0;
+        }
+        if let Some(key) = key {
+            self.state().constants.insert(key, const_idx);
+        }
+        const_idx
+    }
+    fn emit_const(&mut self, value: Value) -> usize {
+        let const_idx = self.make_constant(value);
         self.emit(Op::Const(const_idx));
         const_idx
     }
     fn emit_return(&mut self) {
-        let previous_line = self.previous.line;
-        self.current_chunk().add_op(Op::Return, previous_line);
+        if self.state().kind == FunctionKind::Initializer {
+            // Initializers always return the instance, held in slot zero.
+            self.emit(Op::GetLocal(0));
+        } else {
+            self.emit(Op::Nil);
+        }
+        self.emit(Op::Return);
     }
     fn emit_jump(&mut self, instruction: Op) -> usize {
+        let offset = self.current_chunk().code.len();
         self.emit(instruction);
-        compile_error!("Chapter 22, jumps");
-        self.current_chunk().
+        offset
+    }
+    fn emit_loop(&mut self, loop_start: usize) {
+        let state = self.state();
+        state.jump_target = state.jump_target.max(loop_start);
+        // Step back over the loop instruction itself too.
+        let offset = self.current_chunk().code.len() + JUMP_SIZE - loop_start;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large.");
+        }
+        self.emit(Op::Loop(offset));
+    }
+    fn patch_jump(&mut self, offset: usize) {
+        // Adjust for the jump instruction itself, which the VM has already
+        // stepped past by the time the distance is applied.
+        let target = self.current_chunk().code.len();
+        self.state().jump_target = target;
+        let jump = target - offset - JUMP_SIZE;
+        if jump > u16::MAX as usize {
+            self.error("Too much code to jump over.");
+        }
+        let code = &mut self.current_chunk().code;
+        assert!(
+            matches!(
+                Op::decode(code, offset),
+                Some((Op::Jump(_) | Op::JumpIfFalse(_), JUMP_SIZE))
+            ),
+            "ICE: tried to patch a non-jump instruction at {offset}"
+        );
+        let distance = u16::try_from(jump).unwrap_or(u16::MAX);
+        code[offset + 1..offset + JUMP_SIZE].copy_from_slice(&distance.to_le_bytes());
     }
     fn add_local(&mut self, name: Token) {
+        if self.state().locals.len() == MAX_LOCALS {
+            self.error("Too many local variables in function.");
+            return;
+        }
         let local = Local {
             name,
-            depth: self.scope_depth,
+            depth: self.state().scope_depth,
             init: false,
+            captured: false,
+            initializer: None,
         };
-        self.locals.push(local)
+        self.state().locals.push(local)
     }
     fn block(&mut self) {
         while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
@@ -362,29 +968,52 @@ impl Compiler {
         self.consume(TokenKind::RightBrace, "Expect '}' after block.");
     }
     fn begin_scope(&mut self) {
-        self.scope_depth += 1;
+        self.state().scope_depth += 1;
     }
     fn end_scope(&mut self) {
-        self.scope_depth -= 1;
-        let scope_depth = self.scope_depth;
+        self.state().scope_depth -= 1;
+        let scope_depth = self.state().scope_depth;
         while self
+            .state()
             .locals
             .last()
             .is_some_and(|local| local.depth > scope_depth)
         {
-            self.emit(Op::Pop);
-            self.locals.pop();
+            let local = self.state().locals.pop().expect("checked above");
+            if local.captured {
+                self.emit(Op::CloseUpvalue);
+            } else {
+                self.emit(Op::Pop);
+            }
         }
     }
-    fn end(&mut self) {
+    fn begin_function(&mut self, kind: FunctionKind) {
+        let name = match kind {
+            FunctionKind::Script => None,
+            FunctionKind::Function | FunctionKind::Method | FunctionKind::Initializer => {
+                Some(self.previous.src.as_str().into())
+            }
+        };
+        self.functions.push(FunctionCompiler::new(kind, name));
+    }
+    fn end(&mut self) -> Function {
         self.emit_return();
-        #[cfg(debug_assertions)]
+        let mut state = self
+            .functions
+            .pop()
+            .expect("ICE: ended a function that was never begun");
         if !self.had_error {
-            eprintln!("{}", self.current_chunk().disassemble("code").unwrap())
+            state.function.chunk.optimize();
         }
+        state.function
+    }
+    fn state(&mut self) -> &mut FunctionCompiler {
+        self.functions
+            .last_mut()
+            .expect("ICE: no function is being compiled")
     }
     fn current_chunk(&mut self) -> &mut Chunk {
-        &mut self.chunk
+        &mut self.state().function.chunk
     }
     fn match_t(&mut self, kind: TokenKind) -> bool {
         if !self.check(kind) {
@@ -478,7 +1107,7 @@ impl From<TokenKind> for ParseRule {
         use ParseRule as P;
         use Precedence as Prec;
         match val {
-            TokenKind::LeftParen => P::new(Some(C::grouping), None, Prec::None),
+            TokenKind::LeftParen => P::new(Some(C::grouping), Some(C::call), Prec::Call),
             TokenKind::Minus => P::new(Some(C::unary), Some(C::binary), Prec::Term),
             TokenKind::Plus => P::new(None, Some(C::binary), Prec::Term),
             TokenKind::Slash => P::new(None, Some(C::binary), Prec::Factor),
@@ -496,24 +1125,27 @@ impl From<TokenKind> for ParseRule {
             TokenKind::LessEqual => ParseRule::new(None, Some(C::binary), Prec::Comparison),
             TokenKind::String => ParseRule::new(Some(C::string), None, Prec::None),
             TokenKind::Identifier => ParseRule::new(Some(C::variable), None, Prec::None),
+            TokenKind::Dot => P::new(None, Some(C::dot), Prec::Call),
+            TokenKind::LeftBracket => P::new(Some(C::list), Some(C::index), Prec::Call),
+            TokenKind::LeftBrace => P::new(Some(C::map), None, Prec::None),
+            TokenKind::This => P::new(Some(C::this), None, Prec::None),
+            TokenKind::Super => P::new(Some(C::super_), None, Prec::None),
+            TokenKind::And => P::new(None, Some(C::and), Prec::And),
+            TokenKind::Or => P::new(None, Some(C::or), Prec::Or),
             TokenKind::RightParen
-            | TokenKind::LeftBrace
             | TokenKind::RightBrace
+            | TokenKind::RightBracket
             | TokenKind::Comma
-            | TokenKind::Dot
+            | TokenKind::Colon
             | TokenKind::Semicolon
             | TokenKind::Equal
-            | TokenKind::And
             | TokenKind::Class
             | TokenKind::Else
             | TokenKind::For
             | TokenKind::Fun
             | TokenKind::If
-            | TokenKind::Or
             | TokenKind::Print
             | TokenKind::Return
-            | TokenKind::Super
-            | TokenKind::This
             | TokenKind::Var
             | TokenKind::While
             | TokenKind::Error
@@ -522,9 +1154,172 @@ impl From<TokenKind> for ParseRule {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FunctionKind {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+/// Per-function compilation state. One of these is pushed for every function
+/// body the compiler enters, so nested functions each get their own chunk and locals.
+pub struct FunctionCompiler {
+    function: Function,
+    kind: FunctionKind,
+    scope_depth: usize,
+    locals: Vec<Local>,
+    /// Indices of the numbers and strings already in the chunk's constants.
+    constants: AHashMap<ConstantKey, usize>,
+    /// Offset of every instruction emitted so far, for folding the last few.
+    op_starts: Vec<usize>,
+    /// Furthest offset any jump lands on. Folding must not reach behind it.
+    jump_target: usize,
+}
+
+/// Constants that are deduplicated, compared by bits so `0` and `-0` stay apart.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    Str(Gc<Str>),
+}
+
+impl FunctionCompiler {
+    fn new(kind: FunctionKind, name: Option<Rc<str>>) -> Self {
+        // Slot zero holds the function being called, or the receiver for methods.
+        let slot_zero = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => "this",
+            FunctionKind::Function | FunctionKind::Script => "",
+        };
+        let callee = Local {
+            name: synthetic_token(slot_zero),
+            depth: 0,
+            init: true,
+            captured: false,
+            initializer: None,
+        };
+        Self {
+            function: Function::new(name),
+            kind,
+            scope_depth: 0,
+            locals: vec![callee],
+            constants: AHashMap::new(),
+            op_starts: Vec::new(),
+            jump_target: 0,
+        }
+    }
+}
+
+/// Per-class compilation state, used to validate `this` and `super`.
+pub struct ClassCompiler {
+    has_superclass: bool,
+}
+
+/// A token for a name the compiler introduces itself, such as `this` or `super`.
+fn synthetic_token(src: &str) -> Token {
+    Token {
+        kind: TokenKind::Identifier,
+        src: src.to_string(),
+        ..Default::default()
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Local {
     name: Token,
     depth: usize,
     init: bool,
+    captured: bool,
+    /// First token of the initializer while it is being compiled.
+    initializer: Option<Token>,
+}
+
+fn note(token: &Token, message: &str) -> Note {
+    Note {
+        message: message.to_string(),
+        line: token.line,
+        span: token.span,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn collects_diagnostics() {
+        let source = "var a = ;\nprint 1 +;\n{ var b; var b; }\nprint \"é\" @";
+        let mut heap = Heap::new();
+        let Err(diagnostics) = Compiler::compile(source.to_string(), &mut heap) else {
+            panic!("expected compile errors");
+        };
+        let found: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.line, d.column, &source[d.span.start..d.span.end]))
+            .collect();
+        assert_eq!(
+            found,
+            [(1, 9, ";"), (2, 10, ";"), (3, 14, "b"), (4, 11, "@")]
+        );
+        assert!(diagnostics.iter().all(|d| d.severity == Severity::Error));
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "[line 1] Error at ';': Expect expression."
+        );
+        assert_eq!(diagnostics[3].anchor, Anchor::Bare);
+    }
+
+    #[test]
+    fn rejects_bad_inheritance() {
+        let source = "class A < A {}\nclass B { f() { super.f(); } }\nsuper.f();";
+        let mut heap = Heap::new();
+        let Err(diagnostics) = Compiler::compile(source.to_string(), &mut heap) else {
+            panic!("expected compile errors");
+        };
+        let messages: Vec<_> = diagnostics.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            messages,
+            [
+                "[line 1] Error at 'A': A class can't inherit from itself.",
+                "[line 2] Error at 'super': Can't use 'super' in a class with no superclass.",
+                "[line 3] Error at 'super': Can't use 'super' outside of a class.",
+            ]
+        );
+    }
+
+    #[test]
+    fn deduplicates_constants() {
+        let source = "var a = 1; a = a + 1; print a + \"a\"; print -0; print 0;";
+        let mut heap = Heap::new();
+        let script = Compiler::compile(source.to_string(), &mut heap).unwrap();
+        let constants = &heap.get(script).chunk.constants;
+        // The name `a` and the string "a" share an entry, as do both 1s and both
+        // 0s. Folding `-0` adds its own entry.
+        assert_eq!(constants.len(), 4);
+        assert_eq!(heap.intern("a"), heap.intern("a"));
+    }
+
+    #[test]
+    fn folds_constants() {
+        let source = "print 1 + 2 * 3; print !nil; print \"a\" + \"b\" == \"ab\"; print -\"a\";
+            if (1 > 2) print 1; else print 2;";
+        let mut heap = Heap::new();
+        let script = Compiler::compile(source.to_string(), &mut heap).unwrap();
+        let chunk = &heap.get(script).chunk;
+        let ops: Vec<String> = chunk
+            .ops()
+            .map(|(_, op)| match op {
+                Op::Const(idx) => format!("Const {}", chunk.constants[idx].display(&heap)),
+                op => format!("{op:?}"),
+            })
+            .collect();
+        // Negating a string is left for the VM to reject.
+        assert_eq!(
+            ops,
+            [
+                "Const 7", "Print", "True", "Print", "True", "Print", "Const a", "Negate", "Print",
+                "Const 2", "Print", "Nil", "Return"
+            ]
+        );
+    }
 }
@@ -1,12 +1,17 @@
-use crate::chunk::Chunk;
+use crate::{
+    chunk::Chunk,
+    obj::{Function, Heap},
+    value::ValueKind,
+};
 use std::fmt::Write;
 
 impl crate::chunk::Chunk {
-    pub fn disassemble(&self, name: &str) -> Result<String, std::fmt::Error> {
+    pub fn disassemble(&self, name: &str, heap: &Heap) -> Result<String, std::fmt::Error> {
         let mut f = String::with_capacity(1024 * 64);
         writeln!(f, "=== {name} ===")?;
         let mut last_line = 0;
-        for (index, op) in self.code.iter().enumerate() {
+        let mut ops = self.ops().peekable();
+        while let Some((index, op)) = ops.next() {
             let line = {
                 let current_line = self.lines[index];
                 let text = if current_line != last_line {
@@ -17,8 +22,12 @@ impl crate::chunk::Chunk {
                 last_line = current_line;
                 text
             };
-            write!(f, "{index:0>4} {line} {}", op.disassemble(self).unwrap())?;
-            if index != self.code.len() - 1 {
+            write!(
+                f,
+                "{index:0>4} {line} {}",
+                op.disassemble(self, heap).unwrap()
+            )?;
+            if ops.peek().is_some() {
                 f.push('\n');
             }
         }
@@ -26,8 +35,24 @@ impl crate::chunk::Chunk {
     }
 }
 
+impl Function {
+    /// Disassembles this function and every function declared inside it,
+    /// innermost first.
+    pub fn disassemble(&self, heap: &Heap) -> Result<String, std::fmt::Error> {
+        let mut f = String::new();
+        for constant in &self.chunk.constants {
+            if let ValueKind::Function(inner) = constant.kind() {
+                writeln!(f, "{}", heap.get(inner).disassemble(heap)?)?;
+            }
+        }
+        f.push_str(&self.chunk.disassemble(&self.to_string(), heap)?);
+        Ok(f)
+    }
+}
+
 impl crate::chunk::Op {
-    pub fn disassemble(&self, chunk: &Chunk) -> Result<String, std::fmt::Error> {
+    pub fn disassemble(&self, chunk: &Chunk, heap: &Heap) -> Result<String, std::fmt::Error> {
+        let constant = |idx: usize| chunk.constants[idx].display(heap);
         let mut f = String::with_capacity(1024);
         match self {
             Self::Return => write!(f, "Op::Return"),
@@ -45,13 +70,57 @@ impl crate::chunk::Op {
             Self::Equal => write!(f, "Op::Equal"),
             Self::Greater => write!(f, "Op::Greater"),
             Self::Less => write!(f, "Op::Less"),
-            Self::Const(idx) => write!(f, "Op::Const {idx} {:?}", chunk.constants[*idx]),
+            Self::NotEqual => write!(f, "Op::NotEqual"),
+            Self::GreaterEqual => write!(f, "Op::GreaterEqual"),
+            Self::LessEqual => write!(f, "Op::LessEqual"),
+            Self::AddConst(idx) => write!(f, "Op::AddConst {idx} {}", constant(*idx)),
+            Self::IncrementLocal(idx) => write!(f, "Op::IncrementLocal ({idx})"),
+            Self::Const(idx) => write!(f, "Op::Const {idx} {}", constant(*idx)),
             Self::GetGlobal(idx) => write!(f, "Op::GetGlobal ({idx})"),
             Self::SetGlobal(idx) => write!(f, "Op::SetGlobal ({idx})"),
             Self::GetLocal(idx) => write!(f, "Op::GetLocal ({idx})"),
             Self::SetLocal(idx) => write!(f, "Op::SetLocal ({idx})"),
+            Self::GetUpvalue(idx) => write!(f, "Op::GetUpvalue ({idx})"),
+            Self::SetUpvalue(idx) => write!(f, "Op::SetUpvalue ({idx})"),
             Self::DefineGlobal(idx) => write!(f, "Op::DefineGlobal ({idx})"),
+            Self::Jump(distance) => write!(f, "Op::Jump ({distance})"),
             Self::JumpIfFalse(distance) => write!(f, "Op::JumpIfFalse ({distance})"),
+            Self::JumpIfFalsePop(distance) => write!(f, "Op::JumpIfFalsePop ({distance})"),
+            Self::Loop(distance) => write!(f, "Op::Loop ({distance})"),
+            Self::Call(arg_count) => write!(f, "Op::Call ({arg_count})"),
+            Self::BuildList(count) => write!(f, "Op::BuildList ({count})"),
+            Self::BuildMap(count) => write!(f, "Op::BuildMap ({count})"),
+            Self::IndexGet => write!(f, "Op::IndexGet"),
+            Self::IndexSet => write!(f, "Op::IndexSet"),
+            Self::Closure(idx) => {
+                write!(f, "Op::Closure {idx} {}", constant(*idx))?;
+                if let ValueKind::Function(function) = chunk.constants[*idx].kind() {
+                    for capture in &heap.get(function).upvalues {
+                        let kind = if capture.is_local { "local" } else { "upvalue" };
+                        write!(f, " ({kind} {})", capture.index)?;
+                    }
+                }
+                Ok(())
+            }
+            Self::CloseUpvalue => write!(f, "Op::CloseUpvalue"),
+            Self::Class(idx) => write!(f, "Op::Class {idx} {}", constant(*idx)),
+            Self::GetProperty(idx) => {
+                write!(f, "Op::GetProperty {idx} {}", constant(*idx))
+            }
+            Self::SetProperty(idx) => {
+                write!(f, "Op::SetProperty {idx} {}", constant(*idx))
+            }
+            Self::Method(idx) => write!(f, "Op::Method {idx} {}", constant(*idx)),
+            Self::Invoke(idx, arg_count) => {
+                write!(f, "Op::Invoke ({arg_count} args) {idx} {}", constant(*idx))
+            }
+            Self::Inherit => write!(f, "Op::Inherit"),
+            Self::GetSuper(idx) => write!(f, "Op::GetSuper {idx} {}", constant(*idx)),
+            Self::SuperInvoke(idx, arg_count) => write!(
+                f,
+                "Op::SuperInvoke ({arg_count} args) {idx} {}",
+                constant(*idx)
+            ),
         }?;
         Ok(f)
     }
@@ -0,0 +1,317 @@
+use crate::scan::Span;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "Warning"),
+            Severity::Error => write!(f, "Error"),
+        }
+    }
+}
+
+/// What a diagnostic was reported at, as shown in its one-line form.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Anchor {
+    /// A token, holding its source text.
+    Lexeme(String),
+    /// The end of the input.
+    End,
+    /// Nothing worth quoting, e.g. an error from the scanner itself.
+    Bare,
+}
+
+/// A problem found while compiling, located in the source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub line: usize,
+    /// 1-based column, in characters.
+    pub column: usize,
+    pub span: Span,
+    pub anchor: Anchor,
+    /// Secondary locations that help explain the error.
+    pub notes: Vec<Note>,
+}
+
+/// A secondary label attached to a [`Diagnostic`], pointing elsewhere in the source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Note {
+    pub message: String,
+    pub line: usize,
+    pub span: Span,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[line {}] {}", self.line, self.severity)?;
+        match &self.anchor {
+            Anchor::Lexeme(lexeme) => write!(f, " at '{lexeme}'")?,
+            Anchor::End => write!(f, " at end")?,
+            Anchor::Bare => {}
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+
+/// A span to underline in a rendered snippet.
+struct Label<'a> {
+    line: usize,
+    span: Span,
+    marker: char,
+    accent: &'static str,
+    message: &'a str,
+}
+
+/// Renders diagnostics with the offending source line and a caret under it,
+/// in the style of rustc.
+pub struct Renderer<'a> {
+    source: &'a str,
+    name: Option<&'a str>,
+    color: bool,
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(source: &'a str) -> Self {
+        Self {
+            source,
+            name: None,
+            color: false,
+        }
+    }
+    /// Names the source, usually its path, in the location line.
+    pub fn name(mut self, name: &'a str) -> Self {
+        self.name = Some(name);
+        self
+    }
+    /// Whether to emit ANSI color escapes.
+    pub fn color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        use std::fmt::Write;
+
+        let (severity, accent) = match diagnostic.severity {
+            Severity::Error => ("error", RED),
+            Severity::Warning => ("warning", YELLOW),
+        };
+        let width = std::iter::once(diagnostic.line)
+            .chain(diagnostic.notes.iter().map(|note| note.line))
+            .max()
+            .unwrap_or(0)
+            .to_string()
+            .len();
+        let gutter = " ".repeat(width);
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{}{severity}{}{}: {}{}",
+            self.paint(accent),
+            self.paint(RESET),
+            self.paint(BOLD),
+            diagnostic.message,
+            self.paint(RESET),
+        );
+        let _ = writeln!(
+            out,
+            "{gutter}{}-->{} {}{}:{}",
+            self.paint(BLUE),
+            self.paint(RESET),
+            self.name.map(|name| format!("{name}:")).unwrap_or_default(),
+            diagnostic.line,
+            diagnostic.column,
+        );
+        let _ = writeln!(out, "{gutter} {}|{}", self.paint(BLUE), self.paint(RESET));
+
+        let mut labels: Vec<Label> = std::iter::once(Label {
+            line: diagnostic.line,
+            span: diagnostic.span,
+            marker: '^',
+            accent,
+            message: "",
+        })
+        .chain(diagnostic.notes.iter().map(|note| Label {
+            line: note.line,
+            span: note.span,
+            marker: '-',
+            accent: BLUE,
+            message: &note.message,
+        }))
+        .collect();
+        labels.sort_by_key(|label| label.span.start);
+        for group in labels.chunk_by(|a, b| a.line == b.line) {
+            self.snippet(&mut out, width, group);
+        }
+        out
+    }
+    /// Writes one source line with every label on it underlined. The rightmost
+    /// label's message goes at the end of the marker line and the others hang
+    /// below it, as rustc does.
+    fn snippet(&self, out: &mut String, width: usize, labels: &[Label]) {
+        use std::fmt::Write;
+
+        let first = self.source.floor_char_boundary(labels[0].span.start);
+        let line_start = self.source[..first].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = self.source[first..]
+            .find('\n')
+            .map_or(self.source.len(), |i| first + i);
+        let text = self.source[line_start..line_end].trim_end_matches('\r');
+
+        // (column, markers, label) with columns counted in characters.
+        let mut placed = Vec::with_capacity(labels.len());
+        for label in labels {
+            let start = self.source.floor_char_boundary(label.span.start);
+            let end = self
+                .source
+                .floor_char_boundary(label.span.end.clamp(start, line_end));
+            let column = self.source[line_start..start].chars().count();
+            let length = self.source[start..end].chars().count().max(1);
+            let markers: String = std::iter::repeat_n(label.marker, length).collect();
+            placed.push((column, markers, label));
+        }
+        // Keep tabs so markers line up with the source above them.
+        let indent = |from: usize, to: usize| -> String {
+            self.source[line_start..]
+                .chars()
+                .skip(from)
+                .take(to.saturating_sub(from))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect()
+        };
+
+        let gutter = " ".repeat(width);
+        let bar = format!("{gutter} {}|{}", self.paint(BLUE), self.paint(RESET));
+        let line = labels[0].line;
+        let _ = writeln!(
+            out,
+            "{}{line:>width$} |{} {text}",
+            self.paint(BLUE),
+            self.paint(RESET)
+        );
+
+        let mut row = String::new();
+        let mut cursor = 0;
+        for (column, markers, label) in &placed {
+            row += &indent(cursor, *column);
+            let _ = write!(
+                row,
+                "{}{markers}{}",
+                self.paint(label.accent),
+                self.paint(RESET)
+            );
+            cursor = column + markers.chars().count();
+        }
+        let (_, _, last) = placed.last().expect("snippet without labels");
+        if !last.message.is_empty() {
+            let _ = write!(
+                row,
+                " {}{}{}",
+                self.paint(last.accent),
+                last.message,
+                self.paint(RESET)
+            );
+        }
+        let _ = writeln!(out, "{bar} {row}");
+
+        let hanging = &placed[..placed.len() - 1];
+        if hanging.iter().all(|(_, _, label)| label.message.is_empty()) {
+            return;
+        }
+        // Each hanging message gets a connector row, then sits on its own row
+        // beneath connectors for the labels to its left.
+        for (index, (column, _, label)) in hanging.iter().enumerate().rev() {
+            if label.message.is_empty() {
+                continue;
+            }
+            let mut connectors = String::new();
+            let mut cursor = 0;
+            for (column, _, label) in &hanging[..=index] {
+                if label.message.is_empty() {
+                    continue;
+                }
+                connectors += &indent(cursor, *column);
+                let _ = write!(
+                    connectors,
+                    "{}|{}",
+                    self.paint(label.accent),
+                    self.paint(RESET)
+                );
+                cursor = column + 1;
+            }
+            let _ = writeln!(out, "{bar} {connectors}");
+            let mut row = String::new();
+            let mut cursor = 0;
+            for (column, _, label) in &hanging[..index] {
+                if label.message.is_empty() {
+                    continue;
+                }
+                row += &indent(cursor, *column);
+                let _ = write!(row, "{}|{}", self.paint(label.accent), self.paint(RESET));
+                cursor = column + 1;
+            }
+            row += &indent(cursor, *column);
+            let _ = writeln!(
+                out,
+                "{bar} {row}{}{}{}",
+                self.paint(label.accent),
+                label.message,
+                self.paint(RESET)
+            );
+        }
+    }
+    fn paint(&self, code: &'static str) -> &'static str {
+        if self.color {
+            code
+        } else {
+            ""
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn renders_snippet_with_note() {
+        let source = "{\n  var a = 1;\n  var a = 2;\n}";
+        let diagnostic = Diagnostic {
+            severity: Severity::Error,
+            message: "Already a variable with this name in this scope.".to_string(),
+            line: 3,
+            column: 7,
+            span: Span { start: 21, end: 22 },
+            anchor: Anchor::Lexeme("a".to_string()),
+            notes: vec![Note {
+                message: "variable declared here".to_string(),
+                line: 2,
+                span: Span { start: 8, end: 9 },
+            }],
+        };
+        let rendered = Renderer::new(source).name("test.lox").render(&diagnostic);
+        assert_eq!(
+            rendered,
+            "error: Already a variable with this name in this scope.\n \
+             --> test.lox:3:7\n  \
+             |\n\
+             2 |   var a = 1;\n  \
+             |       - variable declared here\n\
+             3 |   var a = 2;\n  \
+             |       ^\n"
+        );
+    }
+}
@@ -1,8 +1,13 @@
 pub mod chunk;
 pub mod compile;
 pub mod debug;
+pub mod diagnostic;
+pub mod native;
 pub mod obj;
+pub mod optimize;
 pub mod rle;
 pub mod scan;
+pub mod serialize;
 pub mod value;
+pub mod verify;
 pub mod vm;
@@ -1,31 +1,134 @@
-use std::io::Write;
+use std::{
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+};
 
-use rlox::vm::{InterpretResult, Vm};
+use rlox::{
+    compile::Compiler,
+    diagnostic::{Diagnostic, Renderer},
+    obj::Heap,
+    serialize::is_bytecode,
+    vm::{InterpretResult, TraceOptions, Vm},
+};
+
+const USAGE: &str = "Usage: rlox [--color | --no-color] [--print-code] [--trace-exec] [path]
+       rlox [--color | --no-color] [--print-code] compile <path> [-o <output>]";
 
 fn main() {
-    let mut vm = Vm::init();
-    if std::env::args().len() > 2 {
-        eprintln!("Usage: rlox [path]");
-        std::process::exit(64);
+    let mut color = std::io::stderr().is_terminal();
+    let mut output = None;
+    let mut trace = TraceOptions::default();
+    let mut positional = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--color" => color = true,
+            "--no-color" => color = false,
+            "--print-code" => trace.code = true,
+            "--trace-exec" => {
+                trace.disassemble = true;
+                trace.stack = true;
+            }
+            "-o" => output = Some(args.next().unwrap_or_else(|| usage())),
+            _ if arg.starts_with('-') => usage(),
+            _ => positional.push(arg),
+        }
     }
-    if let Some(file) = std::env::args().nth(1) {
-        let src = std::fs::read_to_string(file).unwrap();
-        match vm.interpret(src) {
-            InterpretResult::CompileError => std::process::exit(64),
-            InterpretResult::RuntimeError => std::process::exit(70),
-            InterpretResult::Ok => {}
+
+    match positional.as_slice() {
+        [command, path] if command == "compile" => {
+            let output =
+                output.map_or_else(|| Path::new(path).with_extension("loxc"), PathBuf::from);
+            compile(path, &output, color, trace.code)
+        }
+        _ if output.is_some() => usage(),
+        [path] => run_file(path, color, trace),
+        [] => {
+            let mut vm = Vm::init();
+            vm.set_trace(trace);
+            repl(vm, color)
         }
+        _ => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    std::process::exit(64);
+}
+
+fn run_file(path: &str, color: bool, trace: TraceOptions) {
+    let mut vm = Vm::init();
+    vm.set_trace(trace);
+    let bytes = std::fs::read(path).unwrap();
+    let (result, src) = if is_bytecode(&bytes) {
+        (vm.interpret_bytecode(&bytes), String::new())
     } else {
-        repl(vm)
+        let src = String::from_utf8(bytes).unwrap();
+        (vm.interpret(src.clone()), src)
+    };
+    match result {
+        InterpretResult::CompileError(diagnostics) => {
+            let renderer = Renderer::new(&src).name(path).color(color);
+            report(&renderer, &diagnostics);
+            std::process::exit(64)
+        }
+        InterpretResult::LoadError(err) => {
+            eprintln!("{path}: {err}");
+            std::process::exit(65)
+        }
+        InterpretResult::VerifyError(err) => {
+            eprintln!("{path}: {err}");
+            std::process::exit(65)
+        }
+        InterpretResult::RuntimeError(err) => {
+            eprintln!("{err}");
+            std::process::exit(70)
+        }
+        InterpretResult::Ok => {}
     }
 }
 
-fn repl(mut vm: Vm) {
+/// Compiles `path` to a bytecode file at `output` without running it.
+fn compile(path: &str, output: &Path, color: bool, print_code: bool) {
+    let src = std::fs::read_to_string(path).unwrap();
+    let mut heap = Heap::new();
+    match Compiler::compile(src.clone(), &mut heap) {
+        Ok(script) => {
+            if print_code {
+                eprintln!("{}", heap.get(script).disassemble(&heap).unwrap());
+            }
+            let bytes = heap.get(script).chunk.serialize(&heap);
+            std::fs::write(output, bytes).unwrap();
+        }
+        Err(diagnostics) => {
+            let renderer = Renderer::new(&src).name(path).color(color);
+            report(&renderer, &diagnostics);
+            std::process::exit(64)
+        }
+    }
+}
+
+fn repl(mut vm: Vm, color: bool) {
     loop {
         let mut cmd = String::with_capacity(1024);
         print!("> ");
         std::io::stdout().flush().unwrap();
         std::io::stdin().read_line(&mut cmd).unwrap();
-        vm.interpret(cmd);
+        match vm.interpret(cmd.clone()) {
+            InterpretResult::CompileError(diagnostics) => {
+                report(&Renderer::new(&cmd).color(color), &diagnostics);
+            }
+            InterpretResult::LoadError(err) => eprintln!("{err}"),
+            InterpretResult::VerifyError(err) => eprintln!("{err}"),
+            InterpretResult::RuntimeError(err) => eprintln!("{err}"),
+            InterpretResult::Ok => {}
+        }
+    }
+}
+
+fn report(renderer: &Renderer, diagnostics: &[Diagnostic]) {
+    for diagnostic in diagnostics {
+        eprintln!("{}", renderer.render(diagnostic));
     }
 }
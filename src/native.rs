@@ -0,0 +1,207 @@
+//! The standard library of natives every [`Vm`] starts with.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    obj::{List, Map},
+    value::{Value, ValueKind},
+    vm::Vm,
+};
+
+pub fn define_builtins(vm: &mut Vm) {
+    vm.define_native("clock", 0, clock);
+    vm.define_native("str", 1, str);
+    vm.define_native("num", 1, num);
+    vm.define_native("len", 1, len);
+    vm.define_native("type", 1, type_of);
+    vm.define_native("input", 0, input);
+    vm.define_native("push", 2, push);
+    vm.define_native("pop", 1, pop);
+    vm.define_native("insert", 3, insert);
+    vm.define_native("remove", 2, remove);
+    vm.define_native("slice", 3, slice);
+    vm.define_native("contains", 2, contains);
+    vm.define_native("reverse", 1, reverse);
+    vm.define_native("keys", 1, keys);
+    vm.define_native("values", 1, values);
+    vm.define_native("has", 2, has);
+}
+
+/// Seconds since the Unix epoch, with sub-second precision.
+fn clock(_vm: &mut Vm, _args: &[Value]) -> Result<Value, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|err| err.to_string())?;
+    Ok(Value::from(now.as_secs_f64()))
+}
+
+/// Converts any value to the string `print` would show for it.
+fn str(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    if args[0].is_str() {
+        return Ok(args[0]);
+    }
+    let text = args[0].display(vm.heap()).to_string();
+    Ok(vm.new_string(&text))
+}
+
+/// Parses a string as a number, returning nil if it isn't one.
+fn num(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    match args[0].kind() {
+        ValueKind::Number(_) => Ok(args[0]),
+        ValueKind::Str(_) => {
+            let text = vm.as_str(args[0]).unwrap_or_default();
+            Ok(text.trim().parse::<f64>().map_or(Value::NIL, Value::from))
+        }
+        _ => Err(format!(
+            "Can't convert a {} to a number.",
+            args[0].type_name()
+        )),
+    }
+}
+
+/// Length of a string in characters, a list in items, or a map in entries.
+fn len(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    if let Some(items) = vm.as_list(args[0]) {
+        return Ok(Value::from(items.len() as f64));
+    }
+    if let Some(map) = vm.as_map(args[0]) {
+        return Ok(Value::from(map.len() as f64));
+    }
+    let Some(text) = vm.as_str(args[0]) else {
+        return Err(format!(
+            "Can't take the length of a {}.",
+            args[0].type_name()
+        ));
+    };
+    Ok(Value::from(text.chars().count() as f64))
+}
+
+fn type_of(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    Ok(vm.new_string(args[0].type_name()))
+}
+
+/// Reads one line from stdin without its line ending, or nil at end of input.
+fn input(vm: &mut Vm, _args: &[Value]) -> Result<Value, String> {
+    let mut line = String::new();
+    let read = std::io::stdin()
+        .read_line(&mut line)
+        .map_err(|err| err.to_string())?;
+    if read == 0 {
+        return Ok(Value::NIL);
+    }
+    let line = line.trim_end_matches(['\n', '\r']);
+    Ok(vm.new_string(line))
+}
+
+/// The items of `value`, or an error saying what couldn't be done to it.
+fn items<'a>(vm: &'a Vm, value: Value, action: &str) -> Result<&'a [Value], String> {
+    vm.as_list(value)
+        .ok_or_else(|| format!("Can't {action} a {}.", value.type_name()))
+}
+
+/// Runs `modify` on the items of `value`, or errors saying what couldn't be
+/// done to it.
+fn modify_items<R>(
+    vm: &mut Vm,
+    value: Value,
+    action: &str,
+    modify: impl FnOnce(&mut Vec<Value>) -> R,
+) -> Result<R, String> {
+    vm.modify_list(value, modify)
+        .ok_or_else(|| format!("Can't {action} a {}.", value.type_name()))
+}
+
+/// The map `value` refers to, or an error saying what couldn't be done to it.
+fn map<'a>(vm: &'a Vm, value: Value, action: &str) -> Result<&'a Map, String> {
+    vm.as_map(value)
+        .ok_or_else(|| format!("Can't {action} a {}.", value.type_name()))
+}
+
+/// Like [`List::position`], but also allows the position just past the end.
+fn boundary(index: Value, len: usize) -> Result<usize, String> {
+    match index.kind() {
+        ValueKind::Number(number) if number == len as f64 => Ok(len),
+        _ => List::position(index, len),
+    }
+}
+
+/// Appends a value to the end of a list.
+fn push(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    modify_items(vm, args[0], "push onto", |items| items.push(args[1]))?;
+    Ok(Value::NIL)
+}
+
+/// Removes and returns the last item of a list.
+fn pop(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    modify_items(vm, args[0], "pop from", Vec::pop)?
+        .ok_or_else(|| "Can't pop from an empty list.".to_string())
+}
+
+/// Inserts a value before the item at an index, or at the end.
+fn insert(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    modify_items(vm, args[0], "insert into", |items| {
+        let position = boundary(args[1], items.len())?;
+        items.insert(position, args[2]);
+        Ok(Value::NIL)
+    })?
+}
+
+/// Removes and returns the item at an index of a list, or under a key of a map.
+fn remove(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    if args[0].is_map() {
+        let removed = vm
+            .modify_map(args[0], |map| map.remove(args[1]))
+            .expect("checked that it is a map")?;
+        return removed.ok_or_else(|| format!("Undefined key '{}'.", args[1].display(vm.heap())));
+    }
+    modify_items(vm, args[0], "remove from", |items| {
+        let position = List::position(args[1], items.len())?;
+        Ok(items.remove(position))
+    })?
+}
+
+/// A new list of the items from a start index up to, but not including, an end index.
+fn slice(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    let items = items(vm, args[0], "slice")?;
+    let start = boundary(args[1], items.len())?;
+    let end = boundary(args[2], items.len())?;
+    if end < start {
+        return Err(format!("Slice end {end} is before its start {start}."));
+    }
+    // The items stay reachable through the original list.
+    let sliced = items[start..end].to_vec();
+    Ok(vm.new_list(sliced))
+}
+
+/// Whether a list has an item equal to a value.
+fn contains(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    let items = items(vm, args[0], "search")?;
+    Ok(Value::from(items.contains(&args[1])))
+}
+
+/// Reverses a list in place.
+fn reverse(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    modify_items(vm, args[0], "reverse", |items| items.reverse())?;
+    Ok(Value::NIL)
+}
+
+/// A new list of a map's keys, in insertion order.
+fn keys(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    let map = map(vm, args[0], "take the keys of")?;
+    // The keys stay reachable through the map.
+    let keys = map.entries().map(|(key, _)| key).collect();
+    Ok(vm.new_list(keys))
+}
+
+/// A new list of a map's values, in insertion order.
+fn values(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    let map = map(vm, args[0], "take the values of")?;
+    let values = map.entries().map(|(_, value)| value).collect();
+    Ok(vm.new_list(values))
+}
+
+/// Whether a map has an entry for a key.
+fn has(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    let map = map(vm, args[0], "look up a key in")?;
+    Ok(Value::from(map.get(args[1])?.is_some()))
+}
@@ -1 +1,650 @@
+use std::{cell::RefCell, collections::hash_map::Entry, io::Write, marker::PhantomData, rc::Rc};
 
+use ahash::AHashMap;
+
+use crate::{
+    chunk::Chunk,
+    value::{Value, ValueKind},
+    vm::Vm,
+};
+
+/// Bytes the heap may grow to before the first collection.
+const INITIAL_NEXT_GC: usize = 1024 * 1024;
+/// How much the heap may grow, relative to the live set, before the next collection.
+const HEAP_GROW_FACTOR: usize = 2;
+
+/// A handle to an object of type `T` living in a [`Heap`].
+///
+/// Handles are plain indices, so they are `Copy` and compare by identity.
+/// They stay valid for as long as the object is reachable from the VM's roots.
+pub struct Gc<T> {
+    index: u32,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Gc<T> {
+    pub(crate) fn new(index: usize) -> Self {
+        Self {
+            index: index.try_into().expect("heap exceeded u32::MAX objects"),
+            marker: PhantomData,
+        }
+    }
+    pub fn index(self) -> usize {
+        self.index as usize
+    }
+}
+
+impl<T> Clone for Gc<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Gc<T> {}
+
+impl<T> PartialEq for Gc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index
+    }
+}
+
+impl<T> Eq for Gc<T> {}
+
+impl<T> std::hash::Hash for Gc<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+    }
+}
+
+impl<T> std::fmt::Debug for Gc<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Gc({})", self.index)
+    }
+}
+
+/// An immutable heap-allocated string. Strings are interned, so create them
+/// with [`Heap::intern`] to keep equal strings sharing one handle.
+pub struct Str(pub Rc<str>);
+
+impl std::ops::Deref for Str {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+pub struct Function {
+    pub arity: usize,
+    pub chunk: Chunk,
+    pub name: Option<Rc<str>>,
+    /// Where each of the closure's upvalues is captured from when it is created.
+    pub upvalues: Vec<Capture>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capture {
+    /// Local slot in the enclosing function if `is_local`, otherwise an
+    /// index into the enclosing closure's upvalues.
+    pub index: usize,
+    pub is_local: bool,
+}
+
+impl Function {
+    pub fn new(name: Option<Rc<str>>) -> Self {
+        Self {
+            arity: 0,
+            chunk: Chunk::init(),
+            name,
+            upvalues: Vec::new(),
+        }
+    }
+}
+
+impl std::fmt::Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {name}>"),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+pub struct Closure {
+    pub function: Gc<Function>,
+    pub upvalues: Vec<Gc<Upvalue>>,
+}
+
+pub enum Upvalue {
+    /// The variable still lives on the VM stack at this slot.
+    Open(usize),
+    /// The variable's stack slot is gone; the upvalue owns the value now.
+    Closed(Value),
+}
+
+pub struct Class {
+    pub name: Rc<str>,
+    pub methods: AHashMap<Gc<Str>, Gc<Closure>>,
+}
+
+impl Class {
+    pub fn new(name: Rc<str>) -> Self {
+        Self {
+            name,
+            methods: AHashMap::new(),
+        }
+    }
+}
+
+pub struct Instance {
+    pub class: Gc<Class>,
+    pub fields: AHashMap<Gc<Str>, Value>,
+}
+
+impl Instance {
+    pub fn new(class: Gc<Class>) -> Self {
+        Self {
+            class,
+            fields: AHashMap::new(),
+        }
+    }
+}
+
+/// A growable list of values, created by a `[a, b]` literal.
+pub struct List(pub Vec<Value>);
+
+impl List {
+    /// Converts a Lox index into a position in a list of `len` items, checking
+    /// that it is a whole number in `0..len`.
+    pub fn position(index: Value, len: usize) -> Result<usize, String> {
+        let ValueKind::Number(number) = index.kind() else {
+            return Err(format!(
+                "List index must be a number, not a {}.",
+                index.type_name()
+            ));
+        };
+        if number.fract() != 0.0 {
+            return Err("List index must be a whole number.".to_string());
+        }
+        if number < 0.0 || number >= len as f64 {
+            return Err(format!(
+                "List index {number} is out of bounds for a list of length {len}."
+            ));
+        }
+        Ok(number as usize)
+    }
+}
+
+/// A [`Value`] that can key a [`Map`]. Two keys are equal exactly when their
+/// values are, so numbers are compared by value with `-0` folded into `0`, and
+/// strings by handle, since they're interned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum MapKey {
+    Nil,
+    Bool(bool),
+    /// The bits of the number.
+    Number(u64),
+    Str(Gc<Str>),
+}
+
+impl MapKey {
+    fn new(value: Value) -> Result<Self, String> {
+        match value.kind() {
+            ValueKind::Nil => Ok(MapKey::Nil),
+            ValueKind::Bool(boolean) => Ok(MapKey::Bool(boolean)),
+            // NaN isn't equal to itself, so it could never be looked up again.
+            ValueKind::Number(number) if number.is_nan() => {
+                Err("Can't use NaN as a map key.".to_string())
+            }
+            // `-0` equals `0`, so both share a key. The pattern matches either.
+            ValueKind::Number(0.0) => Ok(MapKey::Number(0.0f64.to_bits())),
+            ValueKind::Number(number) => Ok(MapKey::Number(number.to_bits())),
+            ValueKind::Str(string) => Ok(MapKey::Str(string)),
+            _ => Err(format!("Can't use a {} as a map key.", value.type_name())),
+        }
+    }
+    /// The value this key stands for, with `-0` as `0`.
+    fn value(self) -> Value {
+        match self {
+            MapKey::Nil => Value::NIL,
+            MapKey::Bool(boolean) => Value::from(boolean),
+            MapKey::Number(bits) => Value::from(f64::from_bits(bits)),
+            MapKey::Str(string) => Value::from(string),
+        }
+    }
+}
+
+/// A hash map keyed by strings, numbers, booleans and nil, created by a
+/// `{key: value}` literal. Entries are kept in insertion order.
+#[derive(Default)]
+pub struct Map {
+    /// Position of each key's entry in `entries`.
+    indices: AHashMap<MapKey, usize>,
+    /// Removing an entry leaves `None` in its place, so later positions stay
+    /// valid. The gaps are closed once they outnumber the live entries.
+    entries: Vec<Option<(Value, Value)>>,
+}
+
+impl Map {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The value stored under `key`, or an error if `key` can't be a key.
+    pub fn get(&self, key: Value) -> Result<Option<Value>, String> {
+        let key = MapKey::new(key)?;
+        Ok(self
+            .indices
+            .get(&key)
+            .and_then(|&index| self.entries[index])
+            .map(|(_, value)| value))
+    }
+    /// Stores `value` under `key`, replacing any value already there but
+    /// keeping the key it was first stored under.
+    pub fn insert(&mut self, key: Value, value: Value) -> Result<(), String> {
+        match self.indices.entry(MapKey::new(key)?) {
+            Entry::Occupied(entry) => {
+                if let Some((_, stored)) = &mut self.entries[*entry.get()] {
+                    *stored = value;
+                }
+            }
+            Entry::Vacant(entry) => {
+                let key = entry.key().value();
+                entry.insert(self.entries.len());
+                self.entries.push(Some((key, value)));
+            }
+        }
+        Ok(())
+    }
+    /// Removes `key`, returning the value that was stored under it.
+    pub fn remove(&mut self, key: Value) -> Result<Option<Value>, String> {
+        let Some(index) = self.indices.remove(&MapKey::new(key)?) else {
+            return Ok(None);
+        };
+        let removed = self.entries[index].take().map(|(_, value)| value);
+        if self.entries.len() > 2 * self.indices.len() {
+            self.compact();
+        }
+        Ok(removed)
+    }
+    /// Closes the gaps left by removed entries.
+    fn compact(&mut self) {
+        let mut moved = vec![0; self.entries.len()];
+        let mut live = 0;
+        for (index, entry) in self.entries.iter().enumerate() {
+            if entry.is_some() {
+                moved[index] = live;
+                live += 1;
+            }
+        }
+        self.entries.retain(Option::is_some);
+        for index in self.indices.values_mut() {
+            *index = moved[*index];
+        }
+    }
+    /// Number of entries.
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+    /// Every key and value, in insertion order.
+    pub fn entries(&self) -> impl Iterator<Item = (Value, Value)> + '_ {
+        self.entries.iter().flatten().copied()
+    }
+}
+
+/// A method closure paired with the instance it was accessed on.
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Gc<Closure>,
+}
+
+/// Signature of a function implemented in Rust and callable from Lox.
+/// The arguments have already been checked against the native's arity.
+pub type NativeFn = fn(&mut Vm, &[Value]) -> Result<Value, String>;
+
+pub struct Native {
+    pub name: Rc<str>,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+pub enum Obj {
+    Str(Str),
+    Function(Function),
+    Closure(Closure),
+    Upvalue(Upvalue),
+    Class(Class),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+    Native(Native),
+    List(List),
+    Map(Map),
+}
+
+impl Obj {
+    fn kind(&self) -> &'static str {
+        match self {
+            Obj::Str(_) => "string",
+            Obj::Function(_) => "function",
+            Obj::Closure(_) => "closure",
+            Obj::Upvalue(_) => "upvalue",
+            Obj::Class(_) => "class",
+            Obj::Instance(_) => "instance",
+            Obj::BoundMethod(_) => "bound method",
+            Obj::Native(_) => "native function",
+            Obj::List(_) => "list",
+            Obj::Map(_) => "map",
+        }
+    }
+    /// Approximate number of bytes owned by this object, used to pace collections.
+    fn size(&self) -> usize {
+        let owned = match self {
+            Obj::Str(string) => string.len(),
+            Obj::Function(function) => {
+                function.chunk.code.len()
+                    + function.chunk.constants.len() * std::mem::size_of::<Value>()
+                    + function.upvalues.len() * std::mem::size_of::<Capture>()
+            }
+            Obj::Closure(closure) => closure.upvalues.len() * std::mem::size_of::<Gc<Upvalue>>(),
+            Obj::Upvalue(_) | Obj::BoundMethod(_) | Obj::Native(_) => 0,
+            Obj::Class(class) => {
+                class.methods.len() * std::mem::size_of::<(Gc<Str>, Gc<Closure>)>()
+            }
+            Obj::Instance(instance) => {
+                instance.fields.len() * std::mem::size_of::<(Gc<Str>, Value)>()
+            }
+            Obj::List(list) => list.0.len() * std::mem::size_of::<Value>(),
+            Obj::Map(map) => {
+                map.entries.len() * std::mem::size_of::<Option<(Value, Value)>>()
+                    + map.indices.len() * std::mem::size_of::<(MapKey, usize)>()
+            }
+        };
+        std::mem::size_of::<Obj>() + owned
+    }
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Obj::Str(_) | Obj::Native(_) => {}
+            Obj::Function(function) => {
+                for constant in &function.chunk.constants {
+                    tracer.mark_value(*constant);
+                }
+            }
+            Obj::Closure(closure) => {
+                tracer.mark(closure.function);
+                for upvalue in &closure.upvalues {
+                    tracer.mark(*upvalue);
+                }
+            }
+            Obj::Upvalue(Upvalue::Open(_)) => {}
+            Obj::Upvalue(Upvalue::Closed(value)) => tracer.mark_value(*value),
+            Obj::Class(class) => {
+                for (name, method) in &class.methods {
+                    tracer.mark(*name);
+                    tracer.mark(*method);
+                }
+            }
+            Obj::Instance(instance) => {
+                tracer.mark(instance.class);
+                for (name, value) in &instance.fields {
+                    tracer.mark(*name);
+                    tracer.mark_value(*value);
+                }
+            }
+            Obj::BoundMethod(bound) => {
+                tracer.mark_value(bound.receiver);
+                tracer.mark(bound.method);
+            }
+            Obj::List(list) => {
+                for item in &list.0 {
+                    tracer.mark_value(*item);
+                }
+            }
+            Obj::Map(map) => {
+                for (key, value) in map.entries() {
+                    tracer.mark_value(key);
+                    tracer.mark_value(value);
+                }
+            }
+        }
+    }
+}
+
+/// Implemented by every type that can be stored in the [`Heap`].
+pub trait HeapObj: Sized {
+    fn into_obj(self) -> Obj;
+    fn from_obj(obj: &Obj) -> Option<&Self>;
+    fn from_obj_mut(obj: &mut Obj) -> Option<&mut Self>;
+}
+
+macro_rules! heap_obj {
+    ($($kind:ident),* $(,)?) => {
+        $(
+            impl HeapObj for $kind {
+                fn into_obj(self) -> Obj {
+                    Obj::$kind(self)
+                }
+                fn from_obj(obj: &Obj) -> Option<&Self> {
+                    match obj {
+                        Obj::$kind(inner) => Some(inner),
+                        _ => None,
+                    }
+                }
+                fn from_obj_mut(obj: &mut Obj) -> Option<&mut Self> {
+                    match obj {
+                        Obj::$kind(inner) => Some(inner),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+heap_obj!(
+    Str,
+    Function,
+    Closure,
+    Upvalue,
+    Class,
+    Instance,
+    BoundMethod,
+    Native,
+    List,
+    Map
+);
+
+/// A mark-and-sweep garbage collected object heap.
+///
+/// The heap never collects on its own; the owner decides when to call
+/// [`Heap::collect`] (usually when [`Heap::should_collect`] says so) and
+/// marks its roots through the [`Tracer`] it is handed.
+pub struct Heap {
+    objects: Vec<Option<Obj>>,
+    sizes: Vec<usize>,
+    marks: Vec<bool>,
+    free: Vec<usize>,
+    gray: Vec<usize>,
+    /// Every live string, so equal strings share one object. Entries don't
+    /// keep their strings alive; they're dropped when the string is swept.
+    strings: AHashMap<Rc<str>, Gc<Str>>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    /// Collect before every allocation, to shake out missing roots.
+    pub stress_gc: bool,
+    /// Where allocations and collections are logged, if anywhere.
+    pub log: Option<Log>,
+}
+
+/// A writer a [`Heap`] shares with the VM that owns it, so both write their
+/// diagnostics to the same place.
+pub type Log = Rc<RefCell<dyn Write>>;
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            sizes: Vec::new(),
+            marks: Vec::new(),
+            free: Vec::new(),
+            gray: Vec::new(),
+            strings: AHashMap::new(),
+            bytes_allocated: 0,
+            next_gc: INITIAL_NEXT_GC,
+            stress_gc: false,
+            log: None,
+        }
+    }
+    pub fn alloc<T: HeapObj>(&mut self, data: T) -> Gc<T> {
+        let obj = data.into_obj();
+        let size = obj.size();
+        let kind = obj.kind();
+        self.bytes_allocated += size;
+        let index = if let Some(index) = self.free.pop() {
+            self.objects[index] = Some(obj);
+            self.sizes[index] = size;
+            self.marks[index] = false;
+            index
+        } else {
+            self.objects.push(Some(obj));
+            self.sizes.push(size);
+            self.marks.push(false);
+            self.objects.len() - 1
+        };
+        self.log(format_args!("{index} allocate {size} for {kind}"));
+        Gc::new(index)
+    }
+    /// Returns the string object for `text`, allocating it if no equal string exists.
+    pub fn intern(&mut self, text: &str) -> Gc<Str> {
+        if let Some(string) = self.strings.get(text) {
+            return *string;
+        }
+        let text: Rc<str> = text.into();
+        let string = self.alloc(Str(text.clone()));
+        self.strings.insert(text, string);
+        string
+    }
+    pub fn get<T: HeapObj>(&self, handle: Gc<T>) -> &T {
+        self.objects[handle.index()]
+            .as_ref()
+            .and_then(T::from_obj)
+            .unwrap_or_else(|| panic!("ICE: dangling or mistyped handle {handle:?}"))
+    }
+    pub fn get_mut<T: HeapObj>(&mut self, handle: Gc<T>) -> &mut T {
+        self.objects[handle.index()]
+            .as_mut()
+            .and_then(T::from_obj_mut)
+            .unwrap_or_else(|| panic!("ICE: dangling or mistyped handle {handle:?}"))
+    }
+    /// Updates the byte count after `handle`'s object grew or shrank, so
+    /// collection is timed by what it holds now rather than when it was made.
+    pub fn resize<T: HeapObj>(&mut self, handle: Gc<T>) {
+        let index = handle.index();
+        let size = self.objects[index]
+            .as_ref()
+            .expect("ICE: resized a freed object")
+            .size();
+        self.bytes_allocated = self.bytes_allocated - self.sizes[index] + size;
+        self.sizes[index] = size;
+    }
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+    /// Number of objects currently alive on the heap.
+    pub fn object_count(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+    pub fn should_collect(&self) -> bool {
+        self.stress_gc || self.bytes_allocated > self.next_gc
+    }
+    /// Runs a full collection. `mark_roots` must mark every object the
+    /// caller can still reach without going through the heap.
+    pub fn collect(&mut self, mark_roots: impl FnOnce(&mut Tracer)) {
+        let before = self.bytes_allocated;
+        self.log(format_args!("-- gc begin"));
+
+        mark_roots(&mut Tracer {
+            marks: &mut self.marks,
+            gray: &mut self.gray,
+        });
+        self.trace_references();
+        let marks = &self.marks;
+        self.strings.retain(|_, string| marks[string.index()]);
+        self.sweep();
+        self.next_gc = (self.bytes_allocated * HEAP_GROW_FACTOR).max(INITIAL_NEXT_GC);
+
+        self.log(format_args!("-- gc end"));
+        self.log(format_args!(
+            "   collected {} bytes (from {before} to {}) next at {}",
+            before - self.bytes_allocated,
+            self.bytes_allocated,
+            self.next_gc
+        ));
+    }
+    /// Writes a line to the log, if there is one. Logging is best effort, so
+    /// a failed write is ignored.
+    fn log(&self, line: std::fmt::Arguments) {
+        if let Some(log) = &self.log {
+            let _ = writeln!(log.borrow_mut(), "{line}");
+        }
+    }
+    fn trace_references(&mut self) {
+        let mut tracer = Tracer {
+            marks: &mut self.marks,
+            gray: &mut self.gray,
+        };
+        while let Some(index) = tracer.gray.pop() {
+            let obj = self.objects[index]
+                .as_ref()
+                .expect("ICE: marked a freed object");
+            obj.trace(&mut tracer);
+        }
+    }
+    fn sweep(&mut self) {
+        for index in 0..self.objects.len() {
+            if self.marks[index] {
+                self.marks[index] = false;
+            } else if let Some(obj) = self.objects[index].take() {
+                self.log(format_args!("{index} free type {}", obj.kind()));
+                self.bytes_allocated -= self.sizes[index];
+                self.free.push(index);
+            }
+        }
+    }
+}
+
+/// Marks objects as reachable during a collection.
+pub struct Tracer<'a> {
+    marks: &'a mut Vec<bool>,
+    gray: &'a mut Vec<usize>,
+}
+
+impl Tracer<'_> {
+    pub fn mark<T>(&mut self, handle: Gc<T>) {
+        let index = handle.index();
+        if !self.marks[index] {
+            self.marks[index] = true;
+            self.gray.push(index);
+        }
+    }
+    pub fn mark_value(&mut self, value: Value) {
+        match value.kind() {
+            ValueKind::Str(handle) => self.mark(handle),
+            ValueKind::Function(handle) => self.mark(handle),
+            ValueKind::Closure(handle) => self.mark(handle),
+            ValueKind::Class(handle) => self.mark(handle),
+            ValueKind::Instance(handle) => self.mark(handle),
+            ValueKind::BoundMethod(handle) => self.mark(handle),
+            ValueKind::Native(handle) => self.mark(handle),
+            ValueKind::List(handle) => self.mark(handle),
+            ValueKind::Map(handle) => self.mark(handle),
+            ValueKind::Bool(_) | ValueKind::Number(_) | ValueKind::Nil => {}
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! Peephole optimization of compiled chunks.
+//!
+//! The compiler emits code as it parses, so `!=` becomes `Equal, Not` and
+//! `i = i + 1` reads, adds and writes back in four instructions.
+//! [`Chunk::optimize`] rewrites those sequences into fused instructions once a
+//! function is complete and every jump in it is known.
+
+use ahash::{AHashMap, AHashSet};
+
+use crate::{
+    chunk::{Chunk, Op},
+    value::ValueKind,
+};
+
+/// An instruction of the optimized chunk.
+struct Rewritten {
+    op: Op,
+    /// Original offset of the first instruction it replaces.
+    start: usize,
+    /// Original offset its line and span are taken from.
+    source: usize,
+    /// Original offset it jumps to, if it is a jump.
+    target: Option<usize>,
+}
+
+impl Chunk {
+    /// Rewrites common instruction sequences into fused instructions, keeping
+    /// jump distances and the line and span tables consistent.
+    pub fn optimize(&mut self) {
+        let ops: Vec<(usize, Op)> = self.ops().collect();
+        let end = self.code.len();
+        let next = |index: usize| ops.get(index + 1).map_or(end, |(offset, _)| *offset);
+        let index_of: AHashMap<usize, usize> = ops
+            .iter()
+            .enumerate()
+            .map(|(index, (offset, _))| (*offset, index))
+            .collect();
+        // How many jumps land on each offset.
+        let mut targets: AHashMap<usize, usize> = AHashMap::new();
+        for (index, (_, op)) in ops.iter().enumerate() {
+            if let Some(target) = jump_target(*op, next(index)) {
+                *targets.entry(target).or_default() += 1;
+            }
+        }
+
+        // Pops that a fused jump already does, which nothing else reaches.
+        let mut removed = AHashSet::new();
+        let mut rewritten = Vec::with_capacity(ops.len());
+        let mut index = 0;
+        while index < ops.len() {
+            let (start, op) = ops[index];
+            if removed.contains(&start) {
+                index += 1;
+                continue;
+            }
+            // A sequence can only be fused if nothing jumps into the middle of it.
+            let window: Vec<Op> = ops[index..]
+                .iter()
+                .enumerate()
+                .take(4)
+                .take_while(|(n, (offset, _))| *n == 0 || !targets.contains_key(offset))
+                .map(|(_, (_, op))| *op)
+                .collect();
+            // The fused instruction, how many it replaces, and which of those
+            // it reports errors at.
+            let (fused, count, source) = match window[..] {
+                [Op::GetLocal(a), Op::Const(one), Op::Add, Op::SetLocal(b), ..]
+                    if a == b && self.is_one(one) =>
+                {
+                    (Op::IncrementLocal(a), 4, 2)
+                }
+                [Op::Const(idx), Op::Add, ..] => (Op::AddConst(idx), 2, 1),
+                [Op::Equal, Op::Not, ..] => (Op::NotEqual, 2, 0),
+                [Op::Less, Op::Not, ..] => (Op::GreaterEqual, 2, 0),
+                [Op::Greater, Op::Not, ..] => (Op::LessEqual, 2, 0),
+                [Op::JumpIfFalse(distance), Op::Pop, ..] => {
+                    let target = next(index) + distance;
+                    match self.skippable_pop(&ops, &index_of, &targets, target) {
+                        Some(after) => {
+                            removed.insert(target);
+                            rewritten.push(Rewritten {
+                                op: Op::JumpIfFalsePop(0),
+                                start,
+                                source: start,
+                                target: Some(after),
+                            });
+                            index += 2;
+                            continue;
+                        }
+                        None => (op, 1, 0),
+                    }
+                }
+                _ => (op, 1, 0),
+            };
+            rewritten.push(Rewritten {
+                op: fused,
+                start,
+                source: ops[index + source].0,
+                target: jump_target(fused, next(index + count - 1)),
+            });
+            index += count;
+        }
+
+        // Every jump target is the start of some rewritten instruction, or
+        // the end of the code.
+        let mut new_offsets = AHashMap::with_capacity(rewritten.len() + 1);
+        let mut offset = 0;
+        let mut scratch = Vec::new();
+        for instruction in &rewritten {
+            new_offsets.insert(instruction.start, offset);
+            scratch.clear();
+            instruction.op.encode(&mut scratch);
+            offset += scratch.len();
+        }
+        new_offsets.insert(end, offset);
+
+        let lines: Vec<usize> = expand(self.lines.runs());
+        let spans = expand(self.spans.runs());
+        let mut optimized = Chunk::init();
+        for instruction in rewritten {
+            let start = optimized.code.len();
+            let op = match instruction.target {
+                Some(target) => {
+                    let target = new_offsets[&target];
+                    // Jumps are always three bytes, so the distance can be
+                    // worked out before encoding.
+                    let after = start + 3;
+                    match instruction.op {
+                        Op::Jump(_) => Op::Jump(target - after),
+                        Op::JumpIfFalse(_) => Op::JumpIfFalse(target - after),
+                        Op::JumpIfFalsePop(_) => Op::JumpIfFalsePop(target - after),
+                        Op::Loop(_) => Op::Loop(after - target),
+                        op => unreachable!("ICE: {op:?} has a jump target"),
+                    }
+                }
+                None => instruction.op,
+            };
+            optimized.add_op(op, lines[instruction.source], spans[instruction.source]);
+        }
+        self.code = optimized.code;
+        self.lines = optimized.lines;
+        self.spans = optimized.spans;
+    }
+    fn is_one(&self, idx: usize) -> bool {
+        matches!(self.constants[idx].kind(), ValueKind::Number(n) if n == 1.0)
+    }
+    /// If the `Pop` at `target` is only reached by a single jump, and never by
+    /// falling through, returns the offset after it.
+    fn skippable_pop(
+        &self,
+        ops: &[(usize, Op)],
+        index_of: &AHashMap<usize, usize>,
+        targets: &AHashMap<usize, usize>,
+        target: usize,
+    ) -> Option<usize> {
+        let index = *index_of.get(&target)?;
+        let (_, Op::Pop) = ops[index] else {
+            return None;
+        };
+        let (_, before) = ops[index.checked_sub(1)?];
+        let unreachable = matches!(before, Op::Jump(_) | Op::Loop(_) | Op::Return);
+        (unreachable && targets.get(&target) == Some(&1)).then(|| {
+            ops.get(index + 1)
+                .map_or(self.code.len(), |(offset, _)| *offset)
+        })
+    }
+}
+
+/// Where `op` jumps to, given the offset of the instruction after it.
+fn jump_target(op: Op, next: usize) -> Option<usize> {
+    match op {
+        Op::Jump(distance) | Op::JumpIfFalse(distance) | Op::JumpIfFalsePop(distance) => {
+            Some(next + distance)
+        }
+        Op::Loop(distance) => Some(next - distance),
+        _ => None,
+    }
+}
+
+fn expand<T: Copy>(runs: impl Iterator<Item = (usize, T)>) -> Vec<T> {
+    runs.flat_map(|(len, value)| std::iter::repeat_n(value, len))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        compile::Compiler,
+        obj::Heap,
+        vm::{InterpretResult, Vm},
+    };
+
+    const SOURCE: &str = "
+        var n = 0;
+        for (var i = 0; i < 5; i = i + 1) {
+            if (i != 2) n = n + 1;
+        }
+        var nan = 0 / 0;
+        if (n != 4 or !(nan >= 1) or !(nan <= 1)) undefinedGlobal;
+    ";
+
+    #[test]
+    fn fuses_instructions() {
+        let mut heap = Heap::new();
+        let script = Compiler::compile(SOURCE.to_string(), &mut heap).unwrap();
+        let chunk = &heap.get(script).chunk;
+        chunk.verify(&heap).unwrap();
+        let listing = chunk.disassemble("script", &heap).unwrap();
+        for fused in [
+            "IncrementLocal",
+            "AddConst",
+            "NotEqual",
+            "GreaterEqual",
+            "LessEqual",
+            "JumpIfFalsePop",
+        ] {
+            assert!(listing.contains(fused), "no {fused} in\n{listing}");
+        }
+        assert_eq!(chunk.lines.len(), chunk.code.len());
+        assert_eq!(chunk.spans.len(), chunk.code.len());
+
+        let mut vm = Vm::init();
+        assert!(matches!(
+            vm.interpret(SOURCE.to_string()),
+            InterpretResult::Ok
+        ));
+    }
+}
@@ -24,6 +24,43 @@ impl<T: std::fmt::Debug + Clone + Copy + Default + PartialEq + Eq> RunLengthEnco
             data: value,
         });
     }
+    /// Appends `len` copies of `value`, merging with the last run if it matches.
+    pub fn push_run(&mut self, len: usize, value: T) {
+        if len == 0 {
+            return;
+        }
+        if let Some(last) = self.inner.last_mut() {
+            if value == last.data {
+                last.len += len;
+                return;
+            }
+        }
+        self.inner.push(Run { len, data: value });
+    }
+    /// Shortens to the first `len` elements, splitting a run if needed.
+    pub fn truncate(&mut self, len: usize) {
+        let mut end = 0;
+        for (index, run) in self.inner.iter_mut().enumerate() {
+            if end + run.len >= len {
+                run.len = len - end;
+                let keep = if run.len == 0 { index } else { index + 1 };
+                self.inner.truncate(keep);
+                return;
+            }
+            end += run.len;
+        }
+    }
+    /// Iterates over `(length, value)` pairs for each run.
+    pub fn runs(&self) -> impl Iterator<Item = (usize, T)> + '_ {
+        self.inner.iter().map(|run| (run.len, run.data))
+    }
+    /// Total number of elements, counting every element of every run.
+    pub fn len(&self) -> usize {
+        self.inner.iter().map(|run| run.len).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
     pub fn get(&self, index: usize) -> Option<&T> {
         let mut last_index = 0;
         for item in &self.inner {
@@ -110,4 +147,16 @@ mod test {
             assert_eq!(rle[index], item);
         }
     }
+    #[test]
+    fn truncate() {
+        let mut rle: RunLengthEncoded<usize> = RunLengthEncoded::new();
+        rle.push_run(3, 1);
+        rle.push_run(2, 2);
+        rle.truncate(4);
+        assert_eq!(rle.runs().collect::<Vec<_>>(), [(3, 1), (1, 2)]);
+        rle.truncate(3);
+        assert_eq!(rle.runs().collect::<Vec<_>>(), [(3, 1)]);
+        rle.truncate(0);
+        assert!(rle.is_empty());
+    }
 }
@@ -3,6 +3,12 @@ pub struct Scanner {
     pub current: usize,
     pub line: usize,
     pub src: Vec<char>,
+    /// Byte offsets into the original source matching `start` and `current`.
+    start_byte: usize,
+    current_byte: usize,
+    /// Index into `src` of the first character of the current line.
+    line_start: usize,
+    start_column: usize,
 }
 
 impl Scanner {
@@ -12,17 +18,23 @@ impl Scanner {
             current: 0,
             line: 1,
             src: src.chars().collect(),
+            start_byte: 0,
+            current_byte: 0,
+            line_start: 0,
+            start_column: 1,
         }
     }
     pub fn scan_token(&mut self) -> Token {
         self.skip_whitespace();
         self.start = self.current;
+        self.start_byte = self.current_byte;
+        self.start_column = self.start - self.line_start + 1;
         if self.is_at_end() {
             return self.token(TokenKind::Eof);
         }
         let c = self.advance();
         if c.is_ascii_alphabetic() || c == '_' {
-            while self.peek().is_ascii_alphanumeric() {
+            while self.peek().is_ascii_alphanumeric() || self.peek() == '_' {
                 self.advance();
             }
             let ident = self.identifier();
@@ -36,8 +48,11 @@ impl Scanner {
             ')' => TokenKind::RightParen,
             '{' => TokenKind::LeftBrace,
             '}' => TokenKind::RightBrace,
+            '[' => TokenKind::LeftBracket,
+            ']' => TokenKind::RightBracket,
             ';' => TokenKind::Semicolon,
             ',' => TokenKind::Comma,
+            ':' => TokenKind::Colon,
             '.' => TokenKind::Dot,
             '-' => TokenKind::Minus,
             '+' => TokenKind::Plus,
@@ -82,6 +97,8 @@ impl Scanner {
             kind,
             src: self.src[self.start..self.current].iter().collect(),
             line: self.line,
+            column: self.start_column,
+            span: self.span(),
         }
     }
     fn error_token(&self, msg: impl Into<String>) -> Token {
@@ -89,6 +106,14 @@ impl Scanner {
             kind: TokenKind::Error,
             src: msg.into(),
             line: self.line,
+            column: self.start_column,
+            span: self.span(),
+        }
+    }
+    fn span(&self) -> Span {
+        Span {
+            start: self.start_byte,
+            end: self.current_byte,
         }
     }
     fn is_at_end(&self) -> bool {
@@ -97,6 +122,10 @@ impl Scanner {
     fn advance(&mut self) -> char {
         let out = self.src[self.current];
         self.current += 1;
+        self.current_byte += out.len_utf8();
+        if out == '\n' {
+            self.line_start = self.current;
+        }
         out
     }
     fn match_c(&mut self, expected: char) -> bool {
@@ -106,7 +135,7 @@ impl Scanner {
         if self.src[self.current] != expected {
             return false;
         }
-        self.current += 1;
+        self.advance();
         true
     }
     fn skip_whitespace(&mut self) {
@@ -140,10 +169,7 @@ impl Scanner {
         self.src[self.current]
     }
     fn peek_next(&self) -> char {
-        if self.is_at_end() {
-            return '\0';
-        }
-        self.src[self.current + 1]
+        self.src.get(self.current + 1).copied().unwrap_or('\0')
     }
     fn string(&mut self) -> Token {
         while self.peek() != '"' && !self.is_at_end() {
@@ -187,27 +213,19 @@ impl Scanner {
             'p' => return self.check_keyword(1, 4, "rint", TokenKind::Print),
             'r' => return self.check_keyword(1, 5, "eturn", TokenKind::Return),
             's' => return self.check_keyword(1, 4, "uper", TokenKind::Super),
-            't' => {
-                if self.current - self.start > 1 {
-                    match self.src[self.start + 1] {
-                        'h' => return self.check_keyword(2, 2, "is", TokenKind::This),
-                        'r' => return self.check_keyword(2, 2, "ue", TokenKind::True),
-                        _ => {}
-                    }
-                }
-            }
+            't' if self.current - self.start > 1 => match self.src[self.start + 1] {
+                'h' => return self.check_keyword(2, 2, "is", TokenKind::This),
+                'r' => return self.check_keyword(2, 2, "ue", TokenKind::True),
+                _ => {}
+            },
             'v' => return self.check_keyword(1, 2, "ar", TokenKind::Var),
             'w' => return self.check_keyword(1, 4, "hile", TokenKind::While),
-            'f' => {
-                if self.current - self.start > 1 {
-                    match self.src[self.start + 1] {
-                        'a' => return self.check_keyword(2, 3, "lse", TokenKind::False),
-                        'o' => return self.check_keyword(2, 1, "r", TokenKind::For),
-                        'u' => return self.check_keyword(2, 1, "n", TokenKind::Fun),
-                        _ => {}
-                    }
-                }
-            }
+            'f' if self.current - self.start > 1 => match self.src[self.start + 1] {
+                'a' => return self.check_keyword(2, 3, "lse", TokenKind::False),
+                'o' => return self.check_keyword(2, 1, "r", TokenKind::For),
+                'u' => return self.check_keyword(2, 1, "n", TokenKind::Fun),
+                _ => {}
+            },
             _ => {}
         }
 
@@ -239,6 +257,10 @@ pub struct Token {
     pub kind: TokenKind,
     pub src: String,
     pub line: usize,
+    /// 1-based column, in characters, of the token's first character.
+    pub column: usize,
+    /// Byte offsets of the token in the source.
+    pub span: Span,
 }
 
 impl Default for Token {
@@ -247,17 +269,29 @@ impl Default for Token {
             kind: TokenKind::Error,
             src: "".to_string(),
             line: 0,
+            column: 0,
+            span: Span::default(),
         }
     }
 }
 
+/// A half-open range of byte offsets into the source.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TokenKind {
     LeftParen,
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
+    Colon,
     Dot,
     Minus,
     Plus,
@@ -0,0 +1,421 @@
+//! A binary file format for compiled chunks, so scripts can ship without source.
+//!
+//! A file is [`MAGIC`], a little-endian `u16` [`VERSION`], then the script's
+//! chunk. Every integer after the header is an unsigned LEB128 varint. A chunk is
+//! its encoded instructions, its constant pool, then its line and span tables as
+//! runs. Instructions are stored as-is, so loaded code must be verified before
+//! it runs.
+//! Function constants embed their own chunk recursively.
+
+use std::rc::Rc;
+
+use crate::{
+    chunk::{Chunk, MAX_FUNCTION_DEPTH},
+    obj::{Capture, Function, Heap},
+    scan::Span,
+    value::{Value, ValueKind},
+};
+
+/// The first bytes of every bytecode file.
+pub const MAGIC: &[u8; 4] = b"LOXC";
+/// Bumped whenever the encoding changes incompatibly.
+pub const VERSION: u16 = 2;
+
+const CONST_NUMBER: u8 = 0;
+const CONST_STR: u8 = 1;
+const CONST_FUNCTION: u8 = 2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input doesn't start with [`MAGIC`].
+    BadMagic,
+    /// The file was written by an incompatible version of the format.
+    UnsupportedVersion(u16),
+    UnexpectedEof,
+    InvalidConstant(u8),
+    InvalidUtf8,
+    /// A varint doesn't fit in a `usize`.
+    Overflow,
+    /// A line or span table doesn't have one entry per instruction.
+    TableMismatch,
+    /// Bytes were left over after the script's chunk.
+    TrailingData,
+    /// Function constants are nested more than [`MAX_FUNCTION_DEPTH`] deep.
+    TooDeep,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "Not a Lox bytecode file."),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported bytecode version {version} (expected {VERSION})."
+            ),
+            Self::UnexpectedEof => write!(f, "Unexpected end of bytecode."),
+            Self::InvalidConstant(tag) => write!(f, "Invalid constant tag {tag}."),
+            Self::InvalidUtf8 => write!(f, "Invalid UTF-8 in string constant."),
+            Self::Overflow => write!(f, "Integer out of range."),
+            Self::TableMismatch => write!(f, "Line table doesn't match the code."),
+            Self::TrailingData => write!(f, "Trailing data after chunk."),
+            Self::TooDeep => write!(f, "Functions nested too deeply."),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Whether `bytes` looks like a bytecode file rather than source.
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+impl Chunk {
+    /// Encodes this chunk, with a file header, looking up heap constants in `heap`.
+    pub fn serialize(&self, heap: &Heap) -> Vec<u8> {
+        let mut writer = Writer {
+            out: Vec::with_capacity(1024),
+            heap,
+        };
+        writer.out.extend_from_slice(MAGIC);
+        writer.out.extend_from_slice(&VERSION.to_le_bytes());
+        writer.chunk(self);
+        writer.out
+    }
+    /// Decodes a chunk written by [`Chunk::serialize`], allocating its
+    /// constants in `heap`.
+    pub fn deserialize(bytes: &[u8], heap: &mut Heap) -> Result<Chunk, DecodeError> {
+        let rest = bytes.strip_prefix(MAGIC).ok_or(DecodeError::BadMagic)?;
+        let mut reader = Reader {
+            bytes: rest,
+            pos: 0,
+            depth: 0,
+            heap,
+        };
+        let version = u16::from_le_bytes([reader.byte()?, reader.byte()?]);
+        if version != VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let chunk = reader.chunk()?;
+        if reader.pos != reader.bytes.len() {
+            return Err(DecodeError::TrailingData);
+        }
+        Ok(chunk)
+    }
+}
+
+struct Writer<'a> {
+    out: Vec<u8>,
+    heap: &'a Heap,
+}
+
+impl Writer<'_> {
+    fn uint(&mut self, mut value: usize) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                self.out.push(byte);
+                return;
+            }
+            self.out.push(byte | 0x80);
+        }
+    }
+    fn str(&mut self, text: &str) {
+        self.uint(text.len());
+        self.out.extend_from_slice(text.as_bytes());
+    }
+    fn chunk(&mut self, chunk: &Chunk) {
+        self.uint(chunk.code.len());
+        self.out.extend_from_slice(&chunk.code);
+        self.uint(chunk.constants.len());
+        for constant in &chunk.constants {
+            self.constant(*constant);
+        }
+        self.uint(chunk.lines.runs().count());
+        for (len, line) in chunk.lines.runs() {
+            self.uint(len);
+            self.uint(line);
+        }
+        self.uint(chunk.spans.runs().count());
+        for (len, span) in chunk.spans.runs() {
+            self.uint(len);
+            self.uint(span.start);
+            self.uint(span.end);
+        }
+    }
+    fn constant(&mut self, value: Value) {
+        match value.kind() {
+            ValueKind::Number(number) => {
+                self.out.push(CONST_NUMBER);
+                self.out.extend_from_slice(&number.to_le_bytes());
+            }
+            ValueKind::Str(string) => {
+                let heap = self.heap;
+                self.out.push(CONST_STR);
+                self.str(&heap.get(string).0);
+            }
+            ValueKind::Function(function) => {
+                let heap = self.heap;
+                let function = heap.get(function);
+                self.out.push(CONST_FUNCTION);
+                self.uint(function.arity);
+                match &function.name {
+                    Some(name) => {
+                        self.out.push(1);
+                        self.str(name);
+                    }
+                    None => self.out.push(0),
+                }
+                self.uint(function.upvalues.len());
+                for capture in &function.upvalues {
+                    self.uint(capture.index);
+                    self.out.push(capture.is_local as u8);
+                }
+                self.chunk(&function.chunk);
+            }
+            _ => panic!(
+                "ICE: the compiler never emits {} constants",
+                value.type_name()
+            ),
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    /// How many function constants enclose the chunk being read.
+    depth: usize,
+    heap: &'a mut Heap,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        let byte = *self.bytes.get(self.pos).ok_or(DecodeError::UnexpectedEof)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+    fn take(&mut self, len: usize) -> Result<&[u8], DecodeError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or(DecodeError::UnexpectedEof)?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+    fn uint(&mut self) -> Result<usize, DecodeError> {
+        let mut value: usize = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            let bits = (byte & 0x7f) as usize;
+            if shift >= usize::BITS || (bits << shift) >> shift != bits {
+                return Err(DecodeError::Overflow);
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+    fn str(&mut self) -> Result<Rc<str>, DecodeError> {
+        let len = self.uint()?;
+        let bytes = self.take(len)?;
+        let text = std::str::from_utf8(bytes).map_err(|_| DecodeError::InvalidUtf8)?;
+        Ok(text.into())
+    }
+    /// Caps preallocation so a corrupt count can't exhaust memory up front.
+    fn count(&mut self) -> Result<(usize, usize), DecodeError> {
+        let count = self.uint()?;
+        Ok((count, count.min(self.bytes.len() - self.pos)))
+    }
+    fn chunk(&mut self) -> Result<Chunk, DecodeError> {
+        let len = self.uint()?;
+        let mut chunk = Chunk::init();
+        chunk.code = self.take(len)?.to_vec();
+        let (len, capacity) = self.count()?;
+        chunk.constants.reserve(capacity);
+        for _ in 0..len {
+            let constant = self.constant()?;
+            chunk.add_const(constant);
+        }
+        let mut covered = 0;
+        for _ in 0..self.uint()? {
+            let len = self.run_len(&mut covered, chunk.code.len())?;
+            chunk.lines.push_run(len, self.uint()?);
+        }
+        let mut covered = 0;
+        for _ in 0..self.uint()? {
+            let len = self.run_len(&mut covered, chunk.code.len())?;
+            let start = self.uint()?;
+            let end = self.uint()?;
+            chunk.spans.push_run(len, Span { start, end });
+        }
+        if chunk.lines.len() != chunk.code.len() || chunk.spans.len() != chunk.code.len() {
+            return Err(DecodeError::TableMismatch);
+        }
+        Ok(chunk)
+    }
+    /// Reads the length of a line or span run, adding it to `covered`. Runs
+    /// can't cover more than the `code_len` bytes of code they describe.
+    fn run_len(&mut self, covered: &mut usize, code_len: usize) -> Result<usize, DecodeError> {
+        let len = self.uint()?;
+        *covered = covered
+            .checked_add(len)
+            .filter(|covered| *covered <= code_len)
+            .ok_or(DecodeError::TableMismatch)?;
+        Ok(len)
+    }
+    fn constant(&mut self) -> Result<Value, DecodeError> {
+        match self.byte()? {
+            CONST_NUMBER => {
+                let bytes = self.take(8)?.try_into().expect("took 8 bytes");
+                Ok(Value::from(f64::from_le_bytes(bytes)))
+            }
+            CONST_STR => {
+                let text = self.str()?;
+                Ok(Value::from(self.heap.intern(&text)))
+            }
+            CONST_FUNCTION => {
+                let arity = self.uint()?;
+                let name = match self.byte()? {
+                    0 => None,
+                    _ => Some(self.str()?),
+                };
+                let (len, capacity) = self.count()?;
+                let mut upvalues = Vec::with_capacity(capacity);
+                for _ in 0..len {
+                    let index = self.uint()?;
+                    let is_local = self.byte()? != 0;
+                    upvalues.push(Capture { index, is_local });
+                }
+                if self.depth == MAX_FUNCTION_DEPTH {
+                    return Err(DecodeError::TooDeep);
+                }
+                self.depth += 1;
+                let chunk = self.chunk()?;
+                self.depth -= 1;
+                let function = self.heap.alloc(Function {
+                    arity,
+                    chunk,
+                    name,
+                    upvalues,
+                });
+                Ok(Value::from(function))
+            }
+            tag => Err(DecodeError::InvalidConstant(tag)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        chunk::OpCode,
+        compile::Compiler,
+        vm::{InterpretResult, Vm},
+    };
+
+    const PROGRAM: &str = "
+class Counter {
+  init(start) { this.count = start; }
+  next() {
+    this.count = this.count + 1;
+    return this.count;
+  }
+}
+fun make(label) {
+  var counter = Counter(1.5);
+  fun show() { return label + \" \" + str(counter.next()); }
+  return show;
+}
+var show = make(\"count\");
+show();
+print show();
+";
+
+    #[test]
+    fn round_trip() {
+        let mut heap = Heap::new();
+        let script = Compiler::compile(PROGRAM.to_string(), &mut heap).unwrap();
+        let bytes = heap.get(script).chunk.serialize(&heap);
+        assert!(is_bytecode(&bytes));
+
+        let mut other = Heap::new();
+        let chunk = Chunk::deserialize(&bytes, &mut other).unwrap();
+        assert_eq!(chunk.serialize(&other), bytes);
+
+        let mut vm = Vm::init();
+        assert!(matches!(vm.interpret_bytecode(&bytes), InterpretResult::Ok));
+    }
+
+    #[test]
+    fn round_trip_under_stress_gc() {
+        let mut heap = Heap::new();
+        let script = Compiler::compile(PROGRAM.to_string(), &mut heap).unwrap();
+        let bytes = heap.get(script).chunk.serialize(&heap);
+
+        let mut vm = Vm::init();
+        vm.set_stress_gc(true);
+        vm.set_output(std::io::sink());
+        assert!(matches!(vm.interpret_bytecode(&bytes), InterpretResult::Ok));
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let mut heap = Heap::new();
+        assert_eq!(
+            Chunk::deserialize(b"print 1;", &mut heap).err(),
+            Some(DecodeError::BadMagic)
+        );
+        assert_eq!(
+            Chunk::deserialize(b"LOXC\x09\x00", &mut heap).err(),
+            Some(DecodeError::UnsupportedVersion(9))
+        );
+
+        let script = Compiler::compile("print 1;".to_string(), &mut heap).unwrap();
+        let bytes = heap.get(script).chunk.serialize(&heap);
+        for len in MAGIC.len()..bytes.len() {
+            assert!(Chunk::deserialize(&bytes[..len], &mut heap).is_err());
+        }
+
+        // Line runs long enough to overflow when added up.
+        let mut writer = Writer {
+            out: MAGIC.to_vec(),
+            heap: &heap,
+        };
+        writer.out.extend_from_slice(&VERSION.to_le_bytes());
+        writer.uint(1);
+        writer.out.push(OpCode::Nil as u8);
+        writer.uint(0);
+        writer.uint(2);
+        for (len, line) in [(1, 1), (usize::MAX, 2)] {
+            writer.uint(len);
+            writer.uint(line);
+        }
+        writer.uint(0);
+        let bytes = writer.out;
+        assert_eq!(
+            Chunk::deserialize(&bytes, &mut heap).err(),
+            Some(DecodeError::TableMismatch)
+        );
+
+        // Each function's only constant is the next one in.
+        let mut function = heap.alloc(Function::new(None));
+        for _ in 0..=MAX_FUNCTION_DEPTH {
+            let mut outer = Function::new(None);
+            outer.chunk.constants.push(Value::from(function));
+            function = heap.alloc(outer);
+        }
+        let bytes = heap.get(function).chunk.serialize(&heap);
+        assert_eq!(
+            Chunk::deserialize(&bytes, &mut heap).err(),
+            Some(DecodeError::TooDeep)
+        );
+    }
+}
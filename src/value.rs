@@ -1,41 +1,229 @@
-use std::rc::Rc;
-#[derive(Clone, Debug, PartialEq)]
+//! Lox values.
+//!
+//! By default [`Value`] is a plain enum. The `nan-boxing` feature packs it into
+//! a single `u64` instead, hiding numbers, booleans, nil and heap handles in
+//! the payload bits of a quiet NaN. Code that should work with either
+//! representation matches on [`Value::kind`] and builds values with
+//! `Value::from` or [`Value::NIL`].
+
+use crate::obj::{
+    BoundMethod, Class, Closure, Function, Gc, Heap, Instance, List, Map, Native, Str,
+};
+
+#[cfg(feature = "nan-boxing")]
+mod nan_boxing;
+#[cfg(feature = "nan-boxing")]
+pub use nan_boxing::{Value, ValueKind};
+
+#[cfg(not(feature = "nan-boxing"))]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Value {
     Bool(bool),
     Number(f64),
-    Str(Rc<str>),
+    Str(Gc<Str>),
+    Function(Gc<Function>),
+    Closure(Gc<Closure>),
+    Class(Gc<Class>),
+    Instance(Gc<Instance>),
+    BoundMethod(Gc<BoundMethod>),
+    Native(Gc<Native>),
+    List(Gc<List>),
+    Map(Gc<Map>),
     Nil,
 }
 
+/// The unpacked form of a [`Value`], for matching on. Without NaN-boxing a
+/// value is always unpacked already.
+#[cfg(not(feature = "nan-boxing"))]
+pub type ValueKind = Value;
+
+#[cfg(not(feature = "nan-boxing"))]
+impl Value {
+    pub const NIL: Value = Value::Nil;
+
+    #[inline]
+    pub fn kind(self) -> ValueKind {
+        self
+    }
+}
+
+// Converting from `ValueKind` is a no-op without NaN-boxing.
+#[allow(clippy::useless_conversion)]
+impl From<f64> for Value {
+    fn from(number: f64) -> Self {
+        ValueKind::Number(number).into()
+    }
+}
+
+#[allow(clippy::useless_conversion)]
+impl From<bool> for Value {
+    fn from(boolean: bool) -> Self {
+        ValueKind::Bool(boolean).into()
+    }
+}
+
+macro_rules! from_handle {
+    ($($kind:ident),*) => {
+        $(#[allow(clippy::useless_conversion)]
+        impl From<Gc<$kind>> for Value {
+            fn from(handle: Gc<$kind>) -> Self {
+                ValueKind::$kind(handle).into()
+            }
+        })*
+    };
+}
+
+from_handle!(
+    Str,
+    Function,
+    Closure,
+    Class,
+    Instance,
+    BoundMethod,
+    Native,
+    List,
+    Map
+);
+
 impl Value {
     pub fn is_falsey(&self) -> bool {
-        match self {
-            Self::Bool(val) => !val,
-            Self::Nil => true,
-            _ => true,
+        match self.kind() {
+            ValueKind::Bool(val) => !val,
+            ValueKind::Nil => true,
+            _ => false,
         }
     }
     pub fn is_bool(&self) -> bool {
-        matches!(self, Value::Bool(_))
+        matches!(self.kind(), ValueKind::Bool(_))
     }
     pub fn is_number(&self) -> bool {
-        matches!(self, Value::Number(_))
+        matches!(self.kind(), ValueKind::Number(_))
     }
     pub fn is_str(&self) -> bool {
-        matches!(self, Value::Str(_))
+        matches!(self.kind(), ValueKind::Str(_))
     }
     pub fn is_nil(&self) -> bool {
-        matches!(self, Value::Nil)
+        matches!(self.kind(), ValueKind::Nil)
+    }
+    pub fn is_function(&self) -> bool {
+        matches!(self.kind(), ValueKind::Function(_))
+    }
+    pub fn is_closure(&self) -> bool {
+        matches!(self.kind(), ValueKind::Closure(_))
+    }
+    pub fn is_class(&self) -> bool {
+        matches!(self.kind(), ValueKind::Class(_))
+    }
+    pub fn is_instance(&self) -> bool {
+        matches!(self.kind(), ValueKind::Instance(_))
+    }
+    pub fn is_bound_method(&self) -> bool {
+        matches!(self.kind(), ValueKind::BoundMethod(_))
+    }
+    pub fn is_native(&self) -> bool {
+        matches!(self.kind(), ValueKind::Native(_))
+    }
+    pub fn is_list(&self) -> bool {
+        matches!(self.kind(), ValueKind::List(_))
+    }
+    pub fn is_map(&self) -> bool {
+        matches!(self.kind(), ValueKind::Map(_))
+    }
+    /// The name Lox's `type()` native reports for this value.
+    pub fn type_name(&self) -> &'static str {
+        match self.kind() {
+            ValueKind::Bool(_) => "bool",
+            ValueKind::Number(_) => "number",
+            ValueKind::Str(_) => "string",
+            ValueKind::Function(_)
+            | ValueKind::Closure(_)
+            | ValueKind::BoundMethod(_)
+            | ValueKind::Native(_) => "function",
+            ValueKind::Class(_) => "class",
+            ValueKind::Instance(_) => "instance",
+            ValueKind::List(_) => "list",
+            ValueKind::Map(_) => "map",
+            ValueKind::Nil => "nil",
+        }
+    }
+    /// Formats the value, looking up any heap object it refers to.
+    pub fn display(self, heap: &Heap) -> ValueDisplay<'_> {
+        ValueDisplay { value: self, heap }
     }
 }
 
-impl std::fmt::Display for Value {
+pub struct ValueDisplay<'a> {
+    value: Value,
+    heap: &'a Heap,
+}
+
+impl std::fmt::Display for ValueDisplay<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Value::Bool(val) => write!(f, "{val}"),
-            Value::Number(val) => write!(f, "{val}"),
-            Value::Str(val) => write!(f, "{val}"),
-            Value::Nil => write!(f, "nil"),
+        write_value(f, self.value, self.heap, &mut Vec::new())
+    }
+}
+
+/// How many lists and maps deep [`ValueDisplay`] writes before cutting the
+/// rest short, so a deeply nested value can't overflow the stack.
+const MAX_DISPLAY_DEPTH: usize = 64;
+
+/// Writes `value` for [`ValueDisplay`]. `open` holds the heap index of every
+/// collection being written further up, so one that contains itself is cut
+/// short instead of recursing forever, as is one nested too deeply.
+fn write_value(
+    f: &mut std::fmt::Formatter<'_>,
+    value: Value,
+    heap: &Heap,
+    open: &mut Vec<usize>,
+) -> std::fmt::Result {
+    match value.kind() {
+        ValueKind::Bool(val) => write!(f, "{val}"),
+        ValueKind::Number(val) => write!(f, "{val}"),
+        ValueKind::Str(val) => write!(f, "{}", &**heap.get(val)),
+        ValueKind::Function(val) => write!(f, "{}", heap.get(val)),
+        ValueKind::Closure(val) => write!(f, "{}", heap.get(heap.get(val).function)),
+        ValueKind::Class(val) => write!(f, "{}", heap.get(val).name),
+        ValueKind::Instance(val) => {
+            let class = heap.get(heap.get(val).class);
+            write!(f, "{} instance", class.name)
+        }
+        ValueKind::BoundMethod(val) => {
+            let closure = heap.get(heap.get(val).method);
+            write!(f, "{}", heap.get(closure.function))
+        }
+        ValueKind::Native(_) => write!(f, "<native fn>"),
+        ValueKind::List(val) => {
+            if open.len() == MAX_DISPLAY_DEPTH || open.contains(&val.index()) {
+                return write!(f, "[...]");
+            }
+            open.push(val.index());
+            write!(f, "[")?;
+            for (i, item) in heap.get(val).0.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write_value(f, *item, heap, open)?;
+            }
+            open.pop();
+            write!(f, "]")
+        }
+        ValueKind::Map(val) => {
+            if open.len() == MAX_DISPLAY_DEPTH || open.contains(&val.index()) {
+                return write!(f, "{{...}}");
+            }
+            open.push(val.index());
+            write!(f, "{{")?;
+            for (i, (key, value)) in heap.get(val).entries().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write_value(f, key, heap, open)?;
+                write!(f, ": ")?;
+                write_value(f, value, heap, open)?;
+            }
+            open.pop();
+            write!(f, "}}")
         }
+        ValueKind::Nil => write!(f, "nil"),
     }
 }
@@ -0,0 +1,155 @@
+//! [`Value`] packed into a single `u64`.
+//!
+//! Any `u64` whose quiet NaN bits are all set isn't a number Lox ever produces,
+//! since arithmetic NaNs are canonicalized before boxing. Those leave the low
+//! 50 bits and the sign bit free: nil and the booleans are small tags with the
+//! sign bit clear, and heap handles set the sign bit and store their object
+//! kind above a 32-bit heap index.
+
+use crate::obj::{BoundMethod, Class, Closure, Function, Gc, Instance, List, Map, Native, Str};
+
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+
+const NIL: u64 = QNAN | 1;
+const FALSE: u64 = QNAN | 2;
+const TRUE: u64 = QNAN | 3;
+
+const KIND_SHIFT: u32 = 32;
+const KIND_MASK: u64 = 0xf;
+const INDEX_MASK: u64 = 0xffff_ffff;
+
+const STR: u64 = 0;
+const FUNCTION: u64 = 1;
+const CLOSURE: u64 = 2;
+const CLASS: u64 = 3;
+const INSTANCE: u64 = 4;
+const BOUND_METHOD: u64 = 5;
+const NATIVE: u64 = 6;
+const LIST: u64 = 7;
+const MAP: u64 = 8;
+
+#[derive(Clone, Copy)]
+pub struct Value(u64);
+
+/// The unpacked form of a [`Value`], for matching on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValueKind {
+    Bool(bool),
+    Number(f64),
+    Str(Gc<Str>),
+    Function(Gc<Function>),
+    Closure(Gc<Closure>),
+    Class(Gc<Class>),
+    Instance(Gc<Instance>),
+    BoundMethod(Gc<BoundMethod>),
+    Native(Gc<Native>),
+    List(Gc<List>),
+    Map(Gc<Map>),
+    Nil,
+}
+
+impl Value {
+    pub const NIL: Value = Value(NIL);
+
+    #[inline]
+    pub fn kind(self) -> ValueKind {
+        let bits = self.0;
+        if bits & QNAN != QNAN {
+            return ValueKind::Number(f64::from_bits(bits));
+        }
+        if bits & SIGN_BIT == 0 {
+            return match bits {
+                NIL => ValueKind::Nil,
+                FALSE => ValueKind::Bool(false),
+                TRUE => ValueKind::Bool(true),
+                _ => unreachable!("ICE: invalid boxed value {bits:#x}"),
+            };
+        }
+        let index = (bits & INDEX_MASK) as usize;
+        match (bits >> KIND_SHIFT) & KIND_MASK {
+            STR => ValueKind::Str(Gc::new(index)),
+            FUNCTION => ValueKind::Function(Gc::new(index)),
+            CLOSURE => ValueKind::Closure(Gc::new(index)),
+            CLASS => ValueKind::Class(Gc::new(index)),
+            INSTANCE => ValueKind::Instance(Gc::new(index)),
+            BOUND_METHOD => ValueKind::BoundMethod(Gc::new(index)),
+            NATIVE => ValueKind::Native(Gc::new(index)),
+            LIST => ValueKind::List(Gc::new(index)),
+            MAP => ValueKind::Map(Gc::new(index)),
+            _ => unreachable!("ICE: invalid boxed value {bits:#x}"),
+        }
+    }
+    fn object(kind: u64, index: usize) -> Self {
+        Value(SIGN_BIT | QNAN | kind << KIND_SHIFT | index as u64)
+    }
+}
+
+impl From<ValueKind> for Value {
+    #[inline]
+    fn from(kind: ValueKind) -> Self {
+        match kind {
+            // Keep NaN payloads from colliding with the tagged values.
+            ValueKind::Number(number) if number.is_nan() => Value(f64::NAN.to_bits()),
+            ValueKind::Number(number) => Value(number.to_bits()),
+            ValueKind::Bool(false) => Value(FALSE),
+            ValueKind::Bool(true) => Value(TRUE),
+            ValueKind::Nil => Value(NIL),
+            ValueKind::Str(handle) => Value::object(STR, handle.index()),
+            ValueKind::Function(handle) => Value::object(FUNCTION, handle.index()),
+            ValueKind::Closure(handle) => Value::object(CLOSURE, handle.index()),
+            ValueKind::Class(handle) => Value::object(CLASS, handle.index()),
+            ValueKind::Instance(handle) => Value::object(INSTANCE, handle.index()),
+            ValueKind::BoundMethod(handle) => Value::object(BOUND_METHOD, handle.index()),
+            ValueKind::Native(handle) => Value::object(NATIVE, handle.index()),
+            ValueKind::List(handle) => Value::object(LIST, handle.index()),
+            ValueKind::Map(handle) => Value::object(MAP, handle.index()),
+        }
+    }
+}
+
+/// Same as the enum representation: numbers compare as floats, so `NaN != NaN`,
+/// and everything else by identity.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.kind(), other.kind()) {
+            (ValueKind::Number(a), ValueKind::Number(b)) => a == b,
+            _ => self.0 == other.0,
+        }
+    }
+}
+
+impl std::fmt::Debug for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.kind().fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        assert_eq!(std::mem::size_of::<Value>(), 8);
+        let kinds = [
+            ValueKind::Number(0.0),
+            ValueKind::Number(-1.5),
+            ValueKind::Number(f64::INFINITY),
+            ValueKind::Bool(true),
+            ValueKind::Bool(false),
+            ValueKind::Nil,
+            ValueKind::Str(Gc::new(0)),
+            ValueKind::Closure(Gc::new(7)),
+            ValueKind::List(Gc::new(3)),
+            ValueKind::Map(Gc::new(4)),
+            ValueKind::Native(Gc::new(u32::MAX as usize)),
+        ];
+        for kind in kinds {
+            assert_eq!(Value::from(kind).kind(), kind);
+        }
+        let nan = Value::from(f64::from_bits(QNAN | SIGN_BIT | 5));
+        assert!(matches!(nan.kind(), ValueKind::Number(n) if n.is_nan()));
+        assert_ne!(nan, nan);
+    }
+}
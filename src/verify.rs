@@ -0,0 +1,544 @@
+//! Checks that a chunk is safe to run before the VM ever sees it.
+//!
+//! The VM trusts its bytecode: a bad constant index or local slot panics, and a
+//! bad jump runs off into whatever follows. The compiler never emits those, but
+//! bytecode loaded from disk could contain anything. [`Chunk::verify`] walks
+//! every path through a chunk, tracking the stack height relative to the call
+//! frame, and rejects anything that could index out of bounds. It doesn't
+//! track what type each value has; the VM checks that as it runs and reports a
+//! runtime error for a wrong one.
+
+use std::rc::Rc;
+
+use ahash::AHashSet;
+
+use crate::{
+    chunk::{Chunk, Op, MAX_FUNCTION_DEPTH},
+    obj::{Function, Gc, Heap},
+    value::{Value, ValueKind},
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VerifyErrorKind {
+    /// A constant index past the end of the constant pool.
+    ConstantOutOfRange(usize),
+    /// A constant of the wrong type, e.g. a number where a name is expected.
+    ConstantType {
+        index: usize,
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// Bytes that don't decode to an instruction.
+    InvalidInstruction,
+    /// A jump or loop to an offset outside the chunk or into the middle of
+    /// an instruction.
+    JumpOutOfRange,
+    /// An instruction pops more values than the stack holds.
+    StackUnderflow,
+    /// Two paths reach the same instruction with different stack heights.
+    StackMismatch {
+        expected: usize,
+        found: usize,
+    },
+    LocalOutOfRange(usize),
+    UpvalueOutOfRange(usize),
+    /// Execution can run past the last instruction without returning.
+    FallsOffEnd,
+    /// Functions are nested more than [`MAX_FUNCTION_DEPTH`] deep.
+    TooDeep,
+}
+
+/// Why a chunk was rejected, and where.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VerifyError {
+    pub kind: VerifyErrorKind,
+    /// The function whose chunk is invalid, or `None` for the script.
+    pub function: Option<Rc<str>>,
+    /// Index of the offending instruction.
+    pub offset: usize,
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            VerifyErrorKind::ConstantOutOfRange(index) => {
+                write!(f, "Constant {index} out of range")?
+            }
+            VerifyErrorKind::ConstantType {
+                index,
+                expected,
+                found,
+            } => write!(f, "Constant {index} is a {found}, expected a {expected}")?,
+            VerifyErrorKind::InvalidInstruction => write!(f, "Invalid instruction")?,
+            VerifyErrorKind::JumpOutOfRange => write!(f, "Jump out of range")?,
+            VerifyErrorKind::StackUnderflow => write!(f, "Stack underflow")?,
+            VerifyErrorKind::StackMismatch { expected, found } => write!(
+                f,
+                "Stack height {found} doesn't match {expected} from another path"
+            )?,
+            VerifyErrorKind::LocalOutOfRange(slot) => write!(f, "Local slot {slot} out of range")?,
+            VerifyErrorKind::UpvalueOutOfRange(index) => write!(f, "Upvalue {index} out of range")?,
+            VerifyErrorKind::FallsOffEnd => write!(f, "Execution falls off the end of the chunk")?,
+            VerifyErrorKind::TooDeep => write!(f, "Functions nested too deeply")?,
+        }
+        match &self.function {
+            Some(name) => write!(f, " at {} in {name}().", self.offset),
+            None => write!(f, " at {} in script.", self.offset),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl Chunk {
+    /// Verifies this chunk as a top-level script, along with every function it
+    /// creates a closure for.
+    pub fn verify(&self, heap: &Heap) -> Result<(), VerifyError> {
+        Verifier {
+            chunk: self,
+            heap,
+            name: None,
+            arity: 0,
+            upvalues: 0,
+            depth: 0,
+        }
+        .run(&mut AHashSet::new())
+    }
+}
+
+struct Verifier<'a> {
+    chunk: &'a Chunk,
+    heap: &'a Heap,
+    name: Option<&'a Rc<str>>,
+    arity: usize,
+    upvalues: usize,
+    /// How many functions enclose this one.
+    depth: usize,
+}
+
+impl Verifier<'_> {
+    /// Verifies this chunk and the functions it creates closures for,
+    /// skipping any function already in `verified`.
+    fn run(&self, verified: &mut AHashSet<Gc<Function>>) -> Result<(), VerifyError> {
+        let code = &self.chunk.code;
+        // Decode every instruction up front so jumps can be checked against
+        // instruction boundaries.
+        let mut ops: Vec<Option<(Op, usize)>> = vec![None; code.len()];
+        let mut offset = 0;
+        while offset < code.len() {
+            let decoded = Op::decode(code, offset)
+                .ok_or_else(|| self.error(offset, VerifyErrorKind::InvalidInstruction))?;
+            ops[offset] = Some(decoded);
+            offset += decoded.1;
+        }
+        // Stack height on entry to each instruction, once some path reaches it.
+        let mut heights: Vec<Option<usize>> = vec![None; code.len()];
+        // Slot zero holds the callee, followed by the arguments.
+        let mut pending = vec![(0, self.arity + 1)];
+
+        while let Some((offset, height)) = pending.pop() {
+            if offset == code.len() {
+                return Err(self.error(offset, VerifyErrorKind::FallsOffEnd));
+            }
+            let Some((op, len)) = ops[offset] else {
+                return Err(self.error(offset, VerifyErrorKind::JumpOutOfRange));
+            };
+            match heights[offset] {
+                Some(expected) if expected == height => continue,
+                Some(expected) => {
+                    return Err(self.error(
+                        offset,
+                        VerifyErrorKind::StackMismatch {
+                            expected,
+                            found: height,
+                        },
+                    ))
+                }
+                None => heights[offset] = Some(height),
+            }
+
+            let height = self
+                .check(op, height)
+                .map_err(|kind| self.error(offset, kind))?;
+            if let Op::Closure(idx) = op {
+                self.nested(offset, idx, verified)?;
+            }
+            let next = offset + len;
+            match op {
+                Op::Return => {}
+                Op::Jump(distance) => pending.push((self.target(offset, next, distance)?, height)),
+                Op::JumpIfFalse(distance) | Op::JumpIfFalsePop(distance) => {
+                    pending.push((self.target(offset, next, distance)?, height));
+                    pending.push((next, height));
+                }
+                Op::Loop(distance) => {
+                    let target = next
+                        .checked_sub(distance)
+                        .ok_or_else(|| self.error(offset, VerifyErrorKind::JumpOutOfRange))?;
+                    pending.push((target, height));
+                }
+                _ => pending.push((next, height)),
+            }
+        }
+        Ok(())
+    }
+    /// Checks a single instruction entered with `height` values on the stack,
+    /// returning the height after it.
+    fn check(&self, op: Op, height: usize) -> Result<usize, VerifyErrorKind> {
+        // Each instruction needs `pops` values and leaves `pushes` in their place.
+        let (pops, pushes) = match op {
+            Op::Const(idx) => {
+                self.constant(idx)?;
+                (0, 1)
+            }
+            Op::DefineGlobal(idx) => {
+                self.string(idx)?;
+                (1, 0)
+            }
+            Op::GetGlobal(idx) | Op::Class(idx) => {
+                self.string(idx)?;
+                (0, 1)
+            }
+            Op::SetGlobal(idx) | Op::GetProperty(idx) => {
+                self.string(idx)?;
+                (1, 1)
+            }
+            Op::SetProperty(idx) | Op::Method(idx) | Op::GetSuper(idx) => {
+                self.string(idx)?;
+                (2, 1)
+            }
+            Op::Invoke(idx, arg_count) => {
+                self.string(idx)?;
+                (arg_count.saturating_add(1), 1)
+            }
+            Op::SuperInvoke(idx, arg_count) => {
+                self.string(idx)?;
+                (arg_count.saturating_add(2), 1)
+            }
+            Op::GetLocal(slot) => {
+                self.local(slot, height)?;
+                (0, 1)
+            }
+            Op::SetLocal(slot) => {
+                self.local(slot, height)?;
+                (1, 1)
+            }
+            Op::IncrementLocal(slot) => {
+                self.local(slot, height)?;
+                (0, 1)
+            }
+            Op::AddConst(idx) => {
+                self.constant(idx)?;
+                (1, 1)
+            }
+            Op::GetUpvalue(idx) => {
+                self.upvalue(idx)?;
+                (0, 1)
+            }
+            Op::SetUpvalue(idx) => {
+                self.upvalue(idx)?;
+                (1, 1)
+            }
+            Op::Closure(idx) => {
+                self.captures(idx, height)?;
+                (0, 1)
+            }
+            Op::Call(arg_count) => (arg_count.saturating_add(1), 1),
+            Op::BuildList(count) => (count, 1),
+            Op::BuildMap(count) => (count.saturating_mul(2), 1),
+            Op::IndexSet => (3, 1),
+            Op::Jump(_) | Op::Loop(_) => (0, 0),
+            Op::JumpIfFalse(_) | Op::Not | Op::Negate => (1, 1),
+            Op::JumpIfFalsePop(_) => (1, 0),
+            Op::Nil | Op::True | Op::False => (0, 1),
+            Op::Equal
+            | Op::NotEqual
+            | Op::Greater
+            | Op::GreaterEqual
+            | Op::Less
+            | Op::LessEqual
+            | Op::Add
+            | Op::Subtract
+            | Op::Multiply
+            | Op::Divide
+            | Op::Inherit
+            | Op::IndexGet => (2, 1),
+            Op::CloseUpvalue | Op::Print | Op::Pop | Op::Return => (1, 0),
+        };
+        // Slot zero belongs to the frame, so instructions may not pop it.
+        if pops >= height {
+            return Err(VerifyErrorKind::StackUnderflow);
+        }
+        Ok(height - pops + pushes)
+    }
+    fn target(&self, offset: usize, next: usize, distance: usize) -> Result<usize, VerifyError> {
+        next.checked_add(distance)
+            .filter(|target| *target < self.chunk.code.len())
+            .ok_or_else(|| self.error(offset, VerifyErrorKind::JumpOutOfRange))
+    }
+    fn constant(&self, idx: usize) -> Result<Value, VerifyErrorKind> {
+        self.chunk
+            .constants
+            .get(idx)
+            .copied()
+            .ok_or(VerifyErrorKind::ConstantOutOfRange(idx))
+    }
+    fn string(&self, idx: usize) -> Result<(), VerifyErrorKind> {
+        let constant = self.constant(idx)?;
+        match constant.kind() {
+            ValueKind::Str(_) => Ok(()),
+            _ => Err(VerifyErrorKind::ConstantType {
+                index: idx,
+                expected: "string",
+                found: constant.type_name(),
+            }),
+        }
+    }
+    fn local(&self, slot: usize, height: usize) -> Result<(), VerifyErrorKind> {
+        if slot < height {
+            Ok(())
+        } else {
+            Err(VerifyErrorKind::LocalOutOfRange(slot))
+        }
+    }
+    fn upvalue(&self, idx: usize) -> Result<(), VerifyErrorKind> {
+        if idx < self.upvalues {
+            Ok(())
+        } else {
+            Err(VerifyErrorKind::UpvalueOutOfRange(idx))
+        }
+    }
+    fn function(&self, idx: usize) -> Result<Gc<Function>, VerifyErrorKind> {
+        let constant = self.constant(idx)?;
+        match constant.kind() {
+            ValueKind::Function(function) => Ok(function),
+            _ => Err(VerifyErrorKind::ConstantType {
+                index: idx,
+                expected: "function",
+                found: constant.type_name(),
+            }),
+        }
+    }
+    /// Checks what a closure created with `height` values on the stack captures.
+    fn captures(&self, idx: usize, height: usize) -> Result<(), VerifyErrorKind> {
+        for capture in &self.heap.get(self.function(idx)?).upvalues {
+            if capture.is_local {
+                self.local(capture.index, height)?;
+            } else {
+                self.upvalue(capture.index)?;
+            }
+        }
+        Ok(())
+    }
+    /// Verifies the body of a function a closure is created for at `offset`.
+    /// A body doesn't depend on where its closure is made, so each function
+    /// is only verified once.
+    fn nested(
+        &self,
+        offset: usize,
+        idx: usize,
+        verified: &mut AHashSet<Gc<Function>>,
+    ) -> Result<(), VerifyError> {
+        let handle = self
+            .function(idx)
+            .expect("ICE: closure constant checked before its body");
+        if !verified.insert(handle) {
+            return Ok(());
+        }
+        if self.depth == MAX_FUNCTION_DEPTH {
+            return Err(self.error(offset, VerifyErrorKind::TooDeep));
+        }
+        let function = self.heap.get(handle);
+        Verifier {
+            chunk: &function.chunk,
+            heap: self.heap,
+            name: function.name.as_ref(),
+            arity: function.arity,
+            upvalues: function.upvalues.len(),
+            depth: self.depth + 1,
+        }
+        .run(verified)
+    }
+    fn error(&self, offset: usize, kind: VerifyErrorKind) -> VerifyError {
+        VerifyError {
+            kind,
+            function: self.name.cloned(),
+            offset,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        compile::Compiler,
+        scan::Span,
+        vm::{InterpretResult, Vm},
+    };
+
+    fn chunk(code: &[Op], constants: Vec<Value>) -> Chunk {
+        let mut chunk = Chunk::init();
+        for op in code {
+            chunk.add_op(*op, 1, Span::default());
+        }
+        chunk.constants = constants;
+        chunk
+    }
+
+    fn kind(chunk: &Chunk) -> Option<VerifyErrorKind> {
+        chunk.verify(&Heap::new()).err().map(|err| err.kind)
+    }
+
+    #[test]
+    fn accepts_compiled_code() {
+        let source = "
+class A { init(x) { this.x = x; } get() { return this.x; } }
+class B < A { get() { return super.get() + 1; } }
+fun counter() {
+  var n = 0;
+  fun inc() { n = n + 1; return n; }
+  return inc;
+}
+var c = counter();
+for (var i = 0; i < 3; i = i + 1) { if (i == 1 and true or false) c(); }
+while (c() < 10) {}
+print B(1).get();
+";
+        let mut heap = Heap::new();
+        let script = Compiler::compile(source.to_string(), &mut heap).unwrap();
+        assert_eq!(heap.get(script).chunk.verify(&heap), Ok(()));
+    }
+
+    /// Sequences with the right stack effects but the wrong operand types pass
+    /// verification, so the VM must report them rather than panic.
+    #[test]
+    fn wrong_operand_types_are_runtime_errors() {
+        let mut heap = Heap::new();
+        let name = Value::from(heap.intern("m"));
+        let sequences: [&[Op]; 5] = [
+            &[Op::Nil, Op::Nil, Op::Method(0), Op::Return],
+            &[Op::Nil, Op::Closure(1), Op::Method(0), Op::Return],
+            &[Op::Class(0), Op::Nil, Op::Inherit, Op::Return],
+            &[Op::Nil, Op::Nil, Op::GetSuper(0), Op::Return],
+            &[Op::Nil, Op::Nil, Op::SuperInvoke(0, 0), Op::Return],
+        ];
+        for code in sequences {
+            let mut method = Function::new(Some("m".into()));
+            method.chunk = chunk(&[Op::Nil, Op::Return], vec![]);
+            let method = Value::from(heap.alloc(method));
+            let chunk = chunk(code, vec![name, method]);
+            assert_eq!(chunk.verify(&heap), Ok(()), "{code:?}");
+
+            let mut vm = Vm::init();
+            let result = vm.interpret_bytecode(&chunk.serialize(&heap));
+            assert!(
+                matches!(result, InterpretResult::RuntimeError(_)),
+                "{code:?}"
+            );
+        }
+    }
+
+    /// Nests `depth` functions, each creating `closures` closures of the next.
+    fn nest(heap: &mut Heap, depth: usize, closures: usize) -> Chunk {
+        let mut code = Vec::new();
+        for _ in 0..closures {
+            code.extend([Op::Closure(0), Op::Pop]);
+        }
+        code.extend([Op::Nil, Op::Return]);
+        let mut innermost = Function::new(Some("f".into()));
+        innermost.chunk = chunk(&[Op::Nil, Op::Return], vec![]);
+        let mut function = heap.alloc(innermost);
+        for _ in 0..depth {
+            let mut outer = Function::new(Some("f".into()));
+            outer.chunk = chunk(&code, vec![Value::from(function)]);
+            function = heap.alloc(outer);
+        }
+        chunk(&code, vec![Value::from(function)])
+    }
+
+    #[test]
+    fn verifies_shared_functions_once() {
+        let mut heap = Heap::new();
+        // Checking every closure's body each time would take 50^8 steps.
+        let chunk = nest(&mut heap, 8, 50);
+        assert_eq!(chunk.verify(&heap), Ok(()));
+    }
+
+    #[test]
+    fn rejects_deep_nesting() {
+        let mut heap = Heap::new();
+        let chunk = nest(&mut heap, MAX_FUNCTION_DEPTH - 1, 1);
+        assert_eq!(chunk.verify(&heap), Ok(()));
+        let chunk = nest(&mut heap, MAX_FUNCTION_DEPTH, 1);
+        assert_eq!(
+            chunk.verify(&heap).err().map(|err| err.kind),
+            Some(VerifyErrorKind::TooDeep)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_chunks() {
+        assert_eq!(
+            kind(&chunk(&[Op::Const(3), Op::Return], vec![])),
+            Some(VerifyErrorKind::ConstantOutOfRange(3))
+        );
+        assert_eq!(
+            kind(&chunk(
+                &[Op::GetGlobal(0), Op::Return],
+                vec![Value::from(1.0)]
+            )),
+            Some(VerifyErrorKind::ConstantType {
+                index: 0,
+                expected: "string",
+                found: "number",
+            })
+        );
+        assert_eq!(
+            kind(&chunk(&[Op::Jump(5), Op::Nil, Op::Return], vec![])),
+            Some(VerifyErrorKind::JumpOutOfRange)
+        );
+        assert_eq!(
+            kind(&chunk(&[Op::Loop(5), Op::Return], vec![])),
+            Some(VerifyErrorKind::JumpOutOfRange)
+        );
+        // Lands on the operand of the constant instead of its opcode.
+        assert_eq!(
+            kind(&chunk(
+                &[Op::Jump(1), Op::Const(0), Op::Return],
+                vec![Value::NIL]
+            )),
+            Some(VerifyErrorKind::JumpOutOfRange)
+        );
+        let mut invalid = chunk(&[Op::Nil, Op::Return], vec![]);
+        invalid.code[0] = u8::MAX;
+        assert_eq!(kind(&invalid), Some(VerifyErrorKind::InvalidInstruction));
+        assert_eq!(
+            kind(&chunk(&[Op::Pop, Op::Return], vec![])),
+            Some(VerifyErrorKind::StackUnderflow)
+        );
+        assert_eq!(
+            kind(&chunk(&[Op::GetLocal(1), Op::Return], vec![])),
+            Some(VerifyErrorKind::LocalOutOfRange(1))
+        );
+        assert_eq!(
+            kind(&chunk(&[Op::GetUpvalue(0), Op::Return], vec![])),
+            Some(VerifyErrorKind::UpvalueOutOfRange(0))
+        );
+        assert_eq!(
+            kind(&chunk(&[Op::Nil], vec![])),
+            Some(VerifyErrorKind::FallsOffEnd)
+        );
+        // The true branch pushes an extra value before rejoining.
+        assert_eq!(
+            kind(&chunk(
+                &[Op::True, Op::JumpIfFalse(1), Op::Nil, Op::Nil, Op::Return,],
+                vec![]
+            )),
+            Some(VerifyErrorKind::StackMismatch {
+                expected: 3,
+                found: 2
+            })
+        );
+    }
+}
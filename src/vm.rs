@@ -1,172 +1,904 @@
 use crate::{
     chunk::{Chunk, Op},
     compile::Compiler,
-    value::Value,
+    diagnostic::Diagnostic,
+    obj::{
+        BoundMethod, Class, Closure, Function, Gc, Heap, HeapObj, Instance, List, Log, Map, Native,
+        NativeFn, Str, Upvalue,
+    },
+    scan::Span,
+    serialize::DecodeError,
+    value::{Value, ValueKind},
+    verify::VerifyError,
 };
 use ahash::AHashMap;
-use std::rc::Rc;
+use std::{
+    cell::{Cell, RefCell},
+    io::Write,
+    rc::Rc,
+};
 
-const STACK_PREALLOC: usize = 1024;
+const FRAMES_MAX: usize = 64;
+const STACK_PREALLOC: usize = FRAMES_MAX * 256;
 const GLOBAL_PREALLOC: usize = 1024;
 
 pub struct Vm {
-    chunk: Chunk,
-    ip: usize,
+    frames: Vec<CallFrame>,
     stack: Vec<Value>,
-    globals: AHashMap<Rc<str>, Value>,
+    globals: Globals,
+    /// Upvalues still pointing into the stack, sorted by ascending slot.
+    open_upvalues: Vec<Gc<Upvalue>>,
+    /// The interned name initializers are looked up by.
+    init_string: Gc<Str>,
+    heap: Heap,
+    /// Where `print` writes.
+    output: Box<dyn Write>,
+    /// Where execution traces and the GC log are written.
+    diagnostics: Log,
+    trace: TraceOptions,
+}
+
+/// What the VM writes to its diagnostics writer as it runs. Everything is off
+/// by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TraceOptions {
+    /// Disassemble every function before running it.
+    pub code: bool,
+    /// Disassemble each instruction as it executes.
+    pub disassemble: bool,
+    /// Show the value stack before each instruction.
+    pub stack: bool,
+}
+
+/// Global variables, stored by slot. A name is given a slot the first time
+/// any code refers to it, and keeps it for the life of the VM.
+struct Globals {
+    slots: AHashMap<Gc<Str>, u32>,
+    names: Vec<Gc<Str>>,
+    /// `None` until the variable is defined.
+    values: Vec<Option<Value>>,
+}
+
+impl Globals {
+    fn new() -> Self {
+        Self {
+            slots: AHashMap::with_capacity(GLOBAL_PREALLOC),
+            names: Vec::with_capacity(GLOBAL_PREALLOC),
+            values: Vec::with_capacity(GLOBAL_PREALLOC),
+        }
+    }
+    fn resolve(&mut self, name: Gc<Str>) -> usize {
+        let next = self.names.len() as u32;
+        let slot = *self.slots.entry(name).or_insert(next);
+        if slot == next {
+            self.names.push(name);
+            self.values.push(None);
+        }
+        slot as usize
+    }
+}
+
+struct CallFrame {
+    closure: Gc<Closure>,
+    ip: usize,
+    /// Index of the frame's slot zero on the VM stack.
+    slots: usize,
 }
 
 impl Vm {
     pub fn init() -> Self {
-        Self {
-            chunk: Chunk::init(),
-            ip: 0,
+        let mut heap = Heap::new();
+        let init_string = heap.intern("init");
+        let mut vm = Self {
+            frames: Vec::with_capacity(FRAMES_MAX),
             stack: Vec::with_capacity(STACK_PREALLOC),
-            globals: AHashMap::with_capacity(GLOBAL_PREALLOC),
+            globals: Globals::new(),
+            open_upvalues: Vec::new(),
+            init_string,
+            heap,
+            output: Box::new(std::io::stdout()),
+            diagnostics: Rc::new(RefCell::new(std::io::stderr())),
+            trace: TraceOptions::default(),
+        };
+        crate::native::define_builtins(&mut vm);
+        vm
+    }
+    /// Exposes a Rust function to Lox as the global `name`.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let native = self.alloc(Native {
+            name: name.into(),
+            arity,
+            function,
+        });
+        // Keep the native rooted while its name is interned.
+        self.push(Value::from(native));
+        let name = self.intern(name);
+        self.pop();
+        let slot = self.globals.resolve(name);
+        self.globals.values[slot] = Some(Value::from(native));
+    }
+    /// Interns a Lox string. Intended for natives that return strings.
+    pub fn new_string(&mut self, text: &str) -> Value {
+        Value::from(self.intern(text))
+    }
+    /// The contents of `value` if it is a string.
+    pub fn as_str(&self, value: Value) -> Option<&str> {
+        match value.kind() {
+            ValueKind::Str(string) => Some(&self.heap.get(string).0),
+            _ => None,
         }
     }
+    /// The items of `value` if it is a list.
+    pub fn as_list(&self, value: Value) -> Option<&[Value]> {
+        match value.kind() {
+            ValueKind::List(list) => Some(&self.heap.get(list).0),
+            _ => None,
+        }
+    }
+    /// Runs `modify` on the items of `value` if it is a list, then accounts
+    /// for however much the list grew or shrank.
+    pub fn modify_list<R>(
+        &mut self,
+        value: Value,
+        modify: impl FnOnce(&mut Vec<Value>) -> R,
+    ) -> Option<R> {
+        let ValueKind::List(list) = value.kind() else {
+            return None;
+        };
+        let result = modify(&mut self.heap.get_mut(list).0);
+        self.heap.resize(list);
+        Some(result)
+    }
+    /// The map `value` refers to, if it is one.
+    pub fn as_map(&self, value: Value) -> Option<&Map> {
+        match value.kind() {
+            ValueKind::Map(map) => Some(self.heap.get(map)),
+            _ => None,
+        }
+    }
+    /// Runs `modify` on the map `value` refers to, if it is one, then
+    /// accounts for however much the map grew or shrank.
+    pub fn modify_map<R>(&mut self, value: Value, modify: impl FnOnce(&mut Map) -> R) -> Option<R> {
+        let ValueKind::Map(map) = value.kind() else {
+            return None;
+        };
+        let result = modify(self.heap.get_mut(map));
+        self.heap.resize(map);
+        Some(result)
+    }
+    /// Allocates a Lox list. Collection may run first, so `items` must already
+    /// be reachable, e.g. from a native's arguments.
+    pub fn new_list(&mut self, items: Vec<Value>) -> Value {
+        Value::from(self.alloc(List(items)))
+    }
+    /// Collect garbage before every allocation. Slow; meant for shaking out GC bugs.
+    pub fn set_stress_gc(&mut self, stress_gc: bool) {
+        self.heap.stress_gc = stress_gc;
+    }
+    /// Log every allocation and collection to the diagnostics writer.
+    pub fn set_log_gc(&mut self, log_gc: bool) {
+        self.heap.log = log_gc.then(|| self.diagnostics.clone());
+    }
+    /// Send the output of `print` to `output` instead of stdout.
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Box::new(output);
+    }
+    /// Send execution traces and the GC log to `diagnostics` instead of stderr.
+    pub fn set_diagnostics(&mut self, diagnostics: impl Write + 'static) {
+        self.diagnostics = Rc::new(RefCell::new(diagnostics));
+        if self.heap.log.is_some() {
+            self.heap.log = Some(self.diagnostics.clone());
+        }
+    }
+    /// Choose what is traced to the diagnostics writer.
+    pub fn set_trace(&mut self, trace: TraceOptions) {
+        self.trace = trace;
+    }
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
     pub fn interpret(&mut self, source: String) -> InterpretResult {
-        let Ok(chunk) = Compiler::compile(source) else {
-            return InterpretResult::CompileError;
+        let function = match Compiler::compile(source, &mut self.heap) {
+            Ok(function) => function,
+            Err(diagnostics) => return InterpretResult::CompileError(diagnostics),
         };
-
-        self.chunk = chunk;
-        self.ip = 0;
-        if let Err(err) = self.run() {
-            self.runtime_error(err);
-            InterpretResult::RuntimeError
+        self.interpret_function(function)
+    }
+    /// Runs a script precompiled with [`Chunk::serialize`].
+    pub fn interpret_bytecode(&mut self, bytes: &[u8]) -> InterpretResult {
+        let chunk = match Chunk::deserialize(bytes, &mut self.heap) {
+            Ok(chunk) => chunk,
+            Err(err) => return InterpretResult::LoadError(err),
+        };
+        if let Err(err) = chunk.verify(&self.heap) {
+            return InterpretResult::VerifyError(err);
+        }
+        let mut script = Function::new(None);
+        script.chunk = chunk;
+        // Nothing roots the chunk's constants until the script is on the heap,
+        // so allocate it without giving the collector a chance to run.
+        let function = self.heap.alloc(script);
+        self.interpret_function(function)
+    }
+    fn interpret_function(&mut self, function: Gc<Function>) -> InterpretResult {
+        if self.trace.code {
+            let code = self
+                .heap
+                .get(function)
+                .disassemble(&self.heap)
+                .expect("ICE: failed to disassemble");
+            if let Err(err) = writeln!(self.diagnostics.borrow_mut(), "{code}") {
+                return InterpretResult::RuntimeError(err.into());
+            }
+        }
+        // Keep the function rooted while its closure is allocated.
+        self.push(Value::from(function));
+        let closure = self.alloc(Closure {
+            function,
+            upvalues: Vec::new(),
+        });
+        self.pop();
+        self.push(Value::from(closure));
+        if let Err(err) = self.call(closure, 0).and_then(|_| self.run()) {
+            InterpretResult::RuntimeError(self.runtime_error(err))
         } else {
             InterpretResult::Ok
         }
     }
-    pub fn run(&mut self) -> Result<(), String> {
+    pub fn run(&mut self) -> Result<(), RuntimeError> {
         loop {
-            let instruction = self.chunk.code[self.ip];
-            #[cfg(debug_assertions)]
-            {
-                instruction.disassemble(&self.chunk).unwrap();
-                for entry in &self.stack {
-                    print!("[ {entry:?} ]");
-                }
-                println!();
+            let frame = self.frame();
+            let (instruction, len) =
+                Op::decode(&self.chunk().code, frame.ip).expect("ICE: invalid instruction");
+            if self.trace.stack || self.trace.disassemble {
+                self.trace_instruction(frame.ip, instruction)?;
             }
-            self.ip += 1;
+            self.frame_mut().ip += len;
             match instruction {
                 Op::Const(idx) => {
-                    let constant = self.chunk.constants[idx].clone();
+                    let constant = self.chunk().constants[idx];
                     self.push(constant)
                 }
                 Op::DefineGlobal(idx) => {
-                    let constant = self.chunk.constants[idx].clone();
-                    let Value::Str(name) = constant else {
-                        panic!("ICE: tried to access {idx} in constant table (value {constant})- expected string, was not string");
-                    };
+                    let slot = self.global_slot(idx);
                     let new_val = self.pop();
-                    self.globals.insert(name, new_val);
+                    self.globals.values[slot] = Some(new_val);
                 }
                 Op::GetGlobal(idx) => {
-                    let constant = self.chunk.constants[idx].clone();
-                    let Value::Str(name) = constant else {
-                        panic!("ICE: tried to access {idx} in constant table (value {constant})- expected string, was not string");
-                    };
-                    let Some(value) = self.globals.get(name.as_ref()) else {
-                        return Err(format!("Undefined variable {name}"));
+                    let slot = self.global_slot(idx);
+                    let Some(value) = self.globals.values[slot] else {
+                        return Err(self.undefined_global(slot));
                     };
-                    self.push(value.clone());
+                    self.push(value);
                 }
                 Op::SetGlobal(idx) => {
-                    let constant = self.chunk.constants[idx].clone();
-                    let Value::Str(name) = constant else {
-                        panic!("ICE: tried to access {idx} in constant table (value {constant})- expected string, was not string");
+                    let slot = self.global_slot(idx);
+                    let top = *self.peek(0);
+                    let Some(value) = &mut self.globals.values[slot] else {
+                        return Err(self.undefined_global(slot));
                     };
-                    let top = self.peek(0).clone();
-                    if let Some(value) = self.globals.get_mut(name.as_ref()) {
-                        *value = top;
-                    } else {
-                        return Err(format!("Undefined variable {name}"));
-                    }
+                    *value = top;
                 }
                 Op::GetLocal(idx) => {
-                    let value = self.stack[idx].clone();
+                    let value = self.stack[self.frame().slots + idx];
+                    self.push(value);
+                }
+                Op::SetLocal(idx) => {
+                    let slot = self.frame().slots + idx;
+                    self.stack[slot] = *self.peek(0);
+                }
+                Op::IncrementLocal(idx) => {
+                    let slot = self.frame().slots + idx;
+                    let ValueKind::Number(number) = self.stack[slot].kind() else {
+                        return Err(RuntimeError::new(
+                            ErrorKind::TypeError,
+                            "Operands must be two numbers or two strings.",
+                        ));
+                    };
+                    let value = Value::from(number + 1.0);
+                    self.stack[slot] = value;
+                    self.push(value);
+                }
+                Op::GetUpvalue(idx) => {
+                    let upvalue = self.closure().upvalues[idx];
+                    let value = match *self.heap.get(upvalue) {
+                        Upvalue::Open(slot) => self.stack[slot],
+                        Upvalue::Closed(value) => value,
+                    };
                     self.push(value);
                 }
-                Op::SetLocal(idx) => self.stack[idx] = self.peek(0).clone(),
+                Op::SetUpvalue(idx) => {
+                    let value = *self.peek(0);
+                    let upvalue = self.closure().upvalues[idx];
+                    match self.heap.get_mut(upvalue) {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    }
+                }
+                Op::Jump(distance) => self.frame_mut().ip += distance,
                 Op::JumpIfFalse(distance) => {
                     if self.peek(0).is_falsey() {
-                        self.ip += distance;
+                        self.frame_mut().ip += distance;
+                    }
+                }
+                Op::JumpIfFalsePop(distance) => {
+                    if self.pop().is_falsey() {
+                        self.frame_mut().ip += distance;
+                    }
+                }
+                Op::Loop(distance) => self.frame_mut().ip -= distance,
+                Op::Call(arg_count) => {
+                    let callee = *self.peek(arg_count);
+                    self.call_value(callee, arg_count)?;
+                }
+                Op::Closure(idx) => {
+                    let constant = self.chunk().constants[idx];
+                    let ValueKind::Function(function) = constant.kind() else {
+                        panic!("ICE: tried to access {idx} in constant table (value {constant:?})- expected function, was not function");
+                    };
+                    let captures = self.heap.get(function).upvalues.clone();
+                    let mut upvalues = Vec::with_capacity(captures.len());
+                    for capture in captures {
+                        let upvalue = if capture.is_local {
+                            self.capture_upvalue(self.frame().slots + capture.index)
+                        } else {
+                            self.closure().upvalues[capture.index]
+                        };
+                        upvalues.push(upvalue);
                     }
+                    let closure = self.alloc(Closure { function, upvalues });
+                    self.push(Value::from(closure));
+                }
+                Op::Class(idx) => {
+                    let name = self.read_string(idx);
+                    let name = self.heap.get(name).0.clone();
+                    let class = self.alloc(Class::new(name));
+                    self.push(Value::from(class));
+                }
+                Op::GetProperty(idx) => {
+                    let ValueKind::Instance(instance) = self.peek(0).kind() else {
+                        return Err(RuntimeError::new(
+                            ErrorKind::TypeError,
+                            "Only instances have properties.",
+                        ));
+                    };
+                    let name = self.read_string(idx);
+                    let instance = self.heap.get(instance);
+                    if let Some(value) = instance.fields.get(&name).copied() {
+                        self.pop();
+                        self.push(value);
+                    } else {
+                        let class = instance.class;
+                        self.bind_method(class, name)?;
+                    }
+                }
+                Op::SetProperty(idx) => {
+                    let ValueKind::Instance(instance) = self.peek(1).kind() else {
+                        return Err(RuntimeError::new(
+                            ErrorKind::TypeError,
+                            "Only instances have fields.",
+                        ));
+                    };
+                    let name = self.read_string(idx);
+                    let value = self.pop();
+                    self.heap.get_mut(instance).fields.insert(name, value);
+                    // Pop the instance, leaving the assigned value as the result.
+                    self.pop();
+                    self.push(value);
+                }
+                Op::Method(idx) => {
+                    let name = self.read_string(idx);
+                    // The compiler always emits these with a closure on top of
+                    // a class, but loaded bytecode might not.
+                    let ValueKind::Closure(method) = self.pop().kind() else {
+                        return Err(RuntimeError::new(
+                            ErrorKind::TypeError,
+                            "Method body must be a closure.",
+                        ));
+                    };
+                    let ValueKind::Class(class) = self.peek(0).kind() else {
+                        return Err(RuntimeError::new(
+                            ErrorKind::TypeError,
+                            "Methods can only be defined on classes.",
+                        ));
+                    };
+                    self.heap.get_mut(class).methods.insert(name, method);
+                }
+                Op::Invoke(idx, arg_count) => {
+                    let name = self.read_string(idx);
+                    self.invoke(name, arg_count)?;
+                }
+                Op::Inherit => {
+                    let ValueKind::Class(superclass) = self.peek(1).kind() else {
+                        return Err(RuntimeError::new(
+                            ErrorKind::TypeError,
+                            "Superclass must be a class.",
+                        ));
+                    };
+                    let ValueKind::Class(subclass) = self.pop().kind() else {
+                        return Err(RuntimeError::new(
+                            ErrorKind::TypeError,
+                            "Only classes can inherit.",
+                        ));
+                    };
+                    let methods = self.heap.get(superclass).methods.clone();
+                    self.heap.get_mut(subclass).methods.extend(methods);
+                }
+                Op::GetSuper(idx) => {
+                    let name = self.read_string(idx);
+                    let ValueKind::Class(superclass) = self.pop().kind() else {
+                        return Err(RuntimeError::new(
+                            ErrorKind::TypeError,
+                            "Superclass must be a class.",
+                        ));
+                    };
+                    self.bind_method(superclass, name)?;
+                }
+                Op::SuperInvoke(idx, arg_count) => {
+                    let name = self.read_string(idx);
+                    let ValueKind::Class(superclass) = self.pop().kind() else {
+                        return Err(RuntimeError::new(
+                            ErrorKind::TypeError,
+                            "Superclass must be a class.",
+                        ));
+                    };
+                    self.invoke_from_class(superclass, name, arg_count)?;
+                }
+                Op::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
                 }
                 Op::Add => self.add()?,
-                Op::Subtract => crate::binary_op!(self, Value::Number, -),
-                Op::Multiply => crate::binary_op!(self, Value::Number, *),
-                Op::Divide => crate::binary_op!(self, Value::Number, /),
-                Op::Greater => crate::binary_op!(self, Value::Bool, >),
-                Op::Less => crate::binary_op!(self, Value::Bool, <),
+                Op::AddConst(idx) => {
+                    let constant = self.chunk().constants[idx];
+                    let top = self.stack.len() - 1;
+                    match (self.stack[top].kind(), constant.kind()) {
+                        (ValueKind::Number(a), ValueKind::Number(b)) => {
+                            self.stack[top] = Value::from(a + b)
+                        }
+                        _ => {
+                            self.push(constant);
+                            self.add()?;
+                        }
+                    }
+                }
+                Op::Subtract => crate::binary_op!(self, Value::from, -),
+                Op::Multiply => crate::binary_op!(self, Value::from, *),
+                Op::Divide => crate::binary_op!(self, Value::from, /),
+                Op::Greater => crate::binary_op!(self, Value::from, >),
+                Op::Less => crate::binary_op!(self, Value::from, <),
+                // The negations of `<` and `>`, which differ from `>=` and `<=` for NaN.
+                Op::GreaterEqual => crate::binary_op!(self, |less: bool| Value::from(!less), <),
+                Op::LessEqual => crate::binary_op!(self, |greater: bool| Value::from(!greater), >),
                 Op::Negate => {
-                    if let Value::Number(val) = self.pop() {
-                        self.push(Value::Number(-val));
+                    if let ValueKind::Number(val) = self.pop().kind() {
+                        self.push(Value::from(-val));
                     } else {
-                        return Err("Operand to negate (-) must be a number.".to_string());
+                        return Err(RuntimeError::new(
+                            ErrorKind::TypeError,
+                            "Operand must be a number.",
+                        ));
                     }
                 }
-                Op::Nil => self.push(Value::Nil),
-                Op::True => self.push(Value::Bool(true)),
-                Op::False => self.push(Value::Bool(false)),
+                Op::Nil => self.push(Value::NIL),
+                Op::True => self.push(Value::from(true)),
+                Op::False => self.push(Value::from(false)),
                 Op::Not => {
                     let data = self.pop().is_falsey();
-                    self.push(Value::Bool(data));
+                    self.push(Value::from(data));
                 }
                 Op::Equal => {
                     let a = self.pop();
                     let b = self.pop();
-                    self.push(Value::Bool(a == b))
+                    self.push(Value::from(a == b))
+                }
+                Op::NotEqual => {
+                    let a = self.pop();
+                    let b = self.pop();
+                    self.push(Value::from(a != b))
+                }
+                Op::Print => {
+                    let value = self.pop();
+                    writeln!(self.output, "{}", value.display(&self.heap))?;
                 }
-                Op::Print => println!("{}", self.pop()),
                 Op::Pop => {
                     self.pop();
                 }
-                Op::Return => return Ok(()),
+                Op::BuildList(count) => {
+                    // The items stay on the stack, rooted, until the list holds them.
+                    let start = self.stack.len() - count;
+                    let list = self.alloc(List(self.stack[start..].to_vec()));
+                    self.stack.truncate(start);
+                    self.push(Value::from(list));
+                }
+                Op::BuildMap(count) => {
+                    // Likewise, the entries stay rooted on the stack.
+                    let start = self.stack.len() - 2 * count;
+                    let mut map = Map::new();
+                    for entry in self.stack[start..].chunks_exact(2) {
+                        map.insert(entry[0], entry[1]).map_err(invalid_key)?;
+                    }
+                    let map = self.alloc(map);
+                    self.stack.truncate(start);
+                    self.push(Value::from(map));
+                }
+                Op::IndexGet => {
+                    let index = *self.peek(0);
+                    let item = match self.peek(1).kind() {
+                        ValueKind::List(list) => {
+                            let position = self.list_position(list, index)?;
+                            self.heap.get(list).0[position]
+                        }
+                        ValueKind::Map(map) => match self.heap.get(map).get(index) {
+                            Ok(Some(value)) => value,
+                            Ok(None) => return Err(self.undefined_key(index)),
+                            Err(message) => return Err(invalid_key(message)),
+                        },
+                        _ => return Err(not_indexable()),
+                    };
+                    self.pop();
+                    self.pop();
+                    self.push(item);
+                }
+                Op::IndexSet => {
+                    let value = *self.peek(0);
+                    let index = *self.peek(1);
+                    match self.peek(2).kind() {
+                        ValueKind::List(list) => {
+                            let position = self.list_position(list, index)?;
+                            self.heap.get_mut(list).0[position] = value;
+                        }
+                        ValueKind::Map(map) => {
+                            self.heap
+                                .get_mut(map)
+                                .insert(index, value)
+                                .map_err(invalid_key)?;
+                            self.heap.resize(map);
+                        }
+                        _ => return Err(not_indexable()),
+                    }
+                    self.pop();
+                    self.pop();
+                    self.pop();
+                    self.push(value);
+                }
+                Op::Return => {
+                    let result = self.pop();
+                    let frame = self.frames.pop().expect("ICE: returned with no call frame");
+                    if self.frames.is_empty() {
+                        // Pop the script function itself.
+                        self.pop();
+                        return Ok(());
+                    }
+                    self.close_upvalues(frame.slots);
+                    self.stack.truncate(frame.slots);
+                    self.push(result);
+                }
+            }
+        }
+    }
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), RuntimeError> {
+        match callee.kind() {
+            ValueKind::Closure(closure) => self.call(closure, arg_count),
+            ValueKind::Class(class) => {
+                let slot = self.stack.len() - arg_count - 1;
+                let instance = self.alloc(Instance::new(class));
+                self.stack[slot] = Value::from(instance);
+                let initializer = self.heap.get(class).methods.get(&self.init_string).copied();
+                if let Some(initializer) = initializer {
+                    self.call(initializer, arg_count)
+                } else if arg_count != 0 {
+                    Err(RuntimeError::new(
+                        ErrorKind::ArityMismatch,
+                        format!("Expected 0 arguments but got {arg_count}."),
+                    ))
+                } else {
+                    Ok(())
+                }
+            }
+            ValueKind::BoundMethod(bound) => {
+                let BoundMethod { receiver, method } = *self.heap.get(bound);
+                let slot = self.stack.len() - arg_count - 1;
+                self.stack[slot] = receiver;
+                self.call(method, arg_count)
+            }
+            ValueKind::Native(native) => {
+                let Native {
+                    arity, function, ..
+                } = *self.heap.get(native);
+                if arg_count != arity {
+                    return Err(RuntimeError::new(
+                        ErrorKind::ArityMismatch,
+                        format!("Expected {arity} arguments but got {arg_count}."),
+                    ));
+                }
+                // The arguments stay on the stack, and so rooted, while the native runs.
+                let args_start = self.stack.len() - arg_count;
+                let args = self.stack[args_start..].to_vec();
+                let result = function(self, &args)
+                    .map_err(|message| RuntimeError::new(ErrorKind::Native, message))?;
+                self.stack.truncate(args_start - 1);
+                self.push(result);
+                Ok(())
+            }
+            _ => Err(RuntimeError::new(
+                ErrorKind::TypeError,
+                "Can only call functions and classes.",
+            )),
+        }
+    }
+    fn invoke(&mut self, name: Gc<Str>, arg_count: usize) -> Result<(), RuntimeError> {
+        let ValueKind::Instance(instance) = self.peek(arg_count).kind() else {
+            return Err(RuntimeError::new(
+                ErrorKind::TypeError,
+                "Only instances have methods.",
+            ));
+        };
+        let instance = self.heap.get(instance);
+        // A field holding a callable shadows any method of the same name.
+        if let Some(value) = instance.fields.get(&name).copied() {
+            let slot = self.stack.len() - arg_count - 1;
+            self.stack[slot] = value;
+            return self.call_value(value, arg_count);
+        }
+        let class = instance.class;
+        self.invoke_from_class(class, name, arg_count)
+    }
+    fn invoke_from_class(
+        &mut self,
+        class: Gc<Class>,
+        name: Gc<Str>,
+        arg_count: usize,
+    ) -> Result<(), RuntimeError> {
+        let method = self.heap.get(class).methods.get(&name).copied();
+        let Some(method) = method else {
+            return Err(RuntimeError::new(
+                ErrorKind::UndefinedProperty,
+                format!("Undefined property '{}'.", self.heap.get(name).0),
+            ));
+        };
+        self.call(method, arg_count)
+    }
+    /// Replaces the instance on top of the stack with its method `name` bound to it.
+    fn bind_method(&mut self, class: Gc<Class>, name: Gc<Str>) -> Result<(), RuntimeError> {
+        let method = self.heap.get(class).methods.get(&name).copied();
+        let Some(method) = method else {
+            return Err(RuntimeError::new(
+                ErrorKind::UndefinedProperty,
+                format!("Undefined property '{}'.", self.heap.get(name).0),
+            ));
+        };
+        // The receiver stays on the stack, and so rooted, until the bound method exists.
+        let receiver = *self.peek(0);
+        let bound = self.alloc(BoundMethod { receiver, method });
+        self.pop();
+        self.push(Value::from(bound));
+        Ok(())
+    }
+    fn call(&mut self, closure: Gc<Closure>, arg_count: usize) -> Result<(), RuntimeError> {
+        let arity = self.heap.get(self.heap.get(closure).function).arity;
+        if arg_count != arity {
+            return Err(RuntimeError::new(
+                ErrorKind::ArityMismatch,
+                format!("Expected {arity} arguments but got {arg_count}."),
+            ));
+        }
+        if self.frames.len() == FRAMES_MAX {
+            return Err(RuntimeError::new(
+                ErrorKind::StackOverflow,
+                "Stack overflow.",
+            ));
+        }
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots: self.stack.len() - arg_count - 1,
+        });
+        Ok(())
+    }
+    fn capture_upvalue(&mut self, slot: usize) -> Gc<Upvalue> {
+        let heap = &self.heap;
+        let position = self.open_upvalues.iter().rposition(
+            |upvalue| matches!(*heap.get(*upvalue), Upvalue::Open(open) if open <= slot),
+        );
+        if let Some(position) = position {
+            let existing = self.open_upvalues[position];
+            if matches!(*self.heap.get(existing), Upvalue::Open(open) if open == slot) {
+                return existing;
+            }
+        }
+        let upvalue = self.alloc(Upvalue::Open(slot));
+        let insert_at = position.map_or(0, |position| position + 1);
+        self.open_upvalues.insert(insert_at, upvalue);
+        upvalue
+    }
+    /// Closes every open upvalue pointing at `last` or any slot above it.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last().copied() {
+            let upvalue = self.heap.get_mut(upvalue);
+            let Upvalue::Open(slot) = *upvalue else {
+                panic!("ICE: closed upvalue left in the open upvalue list");
+            };
+            if slot < last {
+                break;
             }
+            *upvalue = Upvalue::Closed(self.stack[slot]);
+            self.open_upvalues.pop();
         }
     }
-    fn add(&mut self) -> Result<(), String> {
+    fn add(&mut self) -> Result<(), RuntimeError> {
         if self.peek(0).is_str() && self.peek(1).is_str() {
             let maybe_b = self.pop();
             let maybe_a = self.pop();
-            let Value::Str(b) = maybe_b else {
+            let ValueKind::Str(b) = maybe_b.kind() else {
                 panic!("data ({maybe_b:?}) guarded as str was not a str");
             };
-            let Value::Str(a) = maybe_a else {
+            let ValueKind::Str(a) = maybe_a.kind() else {
                 panic!("data ({maybe_a:?}) guarded as str was not a str");
             };
-            self.push(Value::Str(format!("{}{}", a.as_ref(), b.as_ref()).into()));
+            let joined = format!("{}{}", &**self.heap.get(a), &**self.heap.get(b));
+            let string = self.intern(&joined);
+            self.push(Value::from(string));
         } else if self.peek(0).is_number() && self.peek(1).is_number() {
             let maybe_b = self.pop();
             let maybe_a = self.pop();
-            let Value::Number(b) = maybe_b else {
+            let ValueKind::Number(b) = maybe_b.kind() else {
                 panic!("data ({maybe_b:?}) guarded as number was not a number");
             };
-            let Value::Number(a) = maybe_a else {
+            let ValueKind::Number(a) = maybe_a.kind() else {
                 panic!("data ({maybe_a:?}) guarded as number was not a number");
             };
-            self.push(Value::Number(a + b));
+            self.push(Value::from(a + b));
         } else {
-            return Err("Operands to + must be two numbers or two strings.".to_string());
+            return Err(RuntimeError::new(
+                ErrorKind::TypeError,
+                "Operands must be two numbers or two strings.",
+            ));
         }
         Ok(())
     }
-    fn runtime_error(&mut self, data: impl std::fmt::Display) {
-        let line = self
-            .chunk
-            .lines
-            .get(self.ip)
-            .expect("self.ip out of line bounds");
-        eprintln!("{data}");
-        eprintln!("[line {line}] in script");
+    /// Checks that `index` is a valid position in `list`.
+    fn list_position(&self, list: Gc<List>, index: Value) -> Result<usize, RuntimeError> {
+        List::position(index, self.heap.get(list).0.len())
+            .map_err(|message| RuntimeError::new(ErrorKind::IndexOutOfBounds, message))
+    }
+    /// The error for looking up `key` in a map that doesn't have it.
+    fn undefined_key(&self, key: Value) -> RuntimeError {
+        RuntimeError::new(
+            ErrorKind::UndefinedKey,
+            format!("Undefined key '{}'.", key.display(&self.heap)),
+        )
+    }
+    /// Writes the stack and the instruction about to run, as chosen by
+    /// [`TraceOptions`].
+    fn trace_instruction(&mut self, offset: usize, instruction: Op) -> std::io::Result<()> {
+        let mut diagnostics = self.diagnostics.borrow_mut();
+        if self.trace.stack {
+            write!(diagnostics, "          ")?;
+            for entry in &self.stack {
+                write!(diagnostics, "[ {} ]", entry.display(&self.heap))?;
+            }
+            writeln!(diagnostics)?;
+        }
+        if self.trace.disassemble {
+            let line = self.chunk().lines[offset];
+            let text = instruction
+                .disassemble(self.chunk(), &self.heap)
+                .expect("ICE: failed to disassemble");
+            writeln!(diagnostics, "{offset:0>4} {line:0>4} {text}")?;
+        }
+        Ok(())
+    }
+    /// Attaches the current call stack to `error` and unwinds the VM.
+    fn runtime_error(&mut self, mut error: RuntimeError) -> RuntimeError {
+        for frame in self.frames.iter().rev() {
+            let function = self.heap.get(self.heap.get(frame.closure).function);
+            let line = *function
+                .chunk
+                .lines
+                .get(frame.ip - 1)
+                .expect("frame.ip out of line bounds");
+            let span = *function
+                .chunk
+                .spans
+                .get(frame.ip - 1)
+                .expect("frame.ip out of span bounds");
+            error.trace.push(TraceFrame {
+                function: function.name.clone(),
+                line,
+                span,
+            });
+        }
         self.reset_stack();
+        error
     }
     fn reset_stack(&mut self) {
         self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+    }
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("ICE: no active call frame")
+    }
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("ICE: no active call frame")
+    }
+    fn closure(&self) -> &Closure {
+        self.heap.get(self.frame().closure)
+    }
+    fn chunk(&self) -> &Chunk {
+        &self.heap.get(self.closure().function).chunk
+    }
+    fn read_string(&self, idx: usize) -> Gc<Str> {
+        let constant = self.chunk().constants[idx];
+        let ValueKind::Str(name) = constant.kind() else {
+            panic!("ICE: tried to access {idx} in constant table (value {constant:?})- expected string, was not string");
+        };
+        name
+    }
+    /// The global slot named by string constant `idx`, resolved through the
+    /// chunk's inline cache.
+    fn global_slot(&mut self, idx: usize) -> usize {
+        let cache = self.chunk().global_slots.get(idx);
+        if let Some(slot) = cache.and_then(Cell::get) {
+            return slot as usize;
+        }
+        let slot = self.globals.resolve(self.read_string(idx));
+        if let Some(cache) = self.chunk().global_slots.get(idx) {
+            cache.set(Some(slot as u32));
+        }
+        slot
+    }
+    fn undefined_global(&self, slot: usize) -> RuntimeError {
+        let name = self.globals.names[slot];
+        RuntimeError::new(
+            ErrorKind::UndefinedVariable,
+            format!("Undefined variable '{}'.", self.heap.get(name).0),
+        )
+    }
+    /// Allocates on the heap, first collecting garbage if the heap has grown enough.
+    fn alloc<T: HeapObj>(&mut self, data: T) -> Gc<T> {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.alloc(data)
+    }
+    fn intern(&mut self, text: &str) -> Gc<Str> {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.intern(text)
+    }
+    pub fn collect_garbage(&mut self) {
+        let Self {
+            frames,
+            stack,
+            globals,
+            open_upvalues,
+            init_string,
+            heap,
+            output: _,
+            diagnostics: _,
+            trace: _,
+        } = self;
+        heap.collect(|tracer| {
+            tracer.mark(*init_string);
+            for value in stack.iter() {
+                tracer.mark_value(*value);
+            }
+            for name in globals.names.iter() {
+                tracer.mark(*name);
+            }
+            for value in globals.values.iter().flatten() {
+                tracer.mark_value(*value);
+            }
+            for frame in frames.iter() {
+                tracer.mark(frame.closure);
+            }
+            for upvalue in open_upvalues.iter() {
+                tracer.mark(*upvalue);
+            }
+        });
     }
     fn push(&mut self, data: Value) {
         self.stack.push(data);
@@ -182,28 +914,479 @@ impl Vm {
 }
 
 pub enum InterpretResult {
-    CompileError,
-    RuntimeError,
+    CompileError(Vec<Diagnostic>),
+    /// Precompiled bytecode couldn't be decoded.
+    LoadError(DecodeError),
+    /// Precompiled bytecode was decoded but isn't safe to run.
+    VerifyError(VerifyError),
+    RuntimeError(RuntimeError),
     Ok,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// An operand or receiver had the wrong type.
+    TypeError,
+    UndefinedVariable,
+    UndefinedProperty,
+    /// A function was called with the wrong number of arguments.
+    ArityMismatch,
+    StackOverflow,
+    /// A native function reported an error.
+    Native,
+    /// Writing program output failed.
+    Io,
+    /// A list index wasn't a whole number within the list's bounds.
+    IndexOutOfBounds,
+    /// A map was indexed with a key it doesn't have.
+    UndefinedKey,
+}
+
+/// An error raised while executing a script, with the call stack at the point it was raised.
+#[derive(Clone, Debug)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    pub message: String,
+    /// Innermost call first.
+    pub trace: Vec<TraceFrame>,
+}
+
+impl RuntimeError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            trace: Vec::new(),
+        }
+    }
+}
+
+/// The error for indexing a value that isn't a list or a map.
+fn not_indexable() -> RuntimeError {
+    RuntimeError::new(ErrorKind::TypeError, "Only lists and maps can be indexed.")
+}
+
+/// The error for a map key [`Map`] refuses, such as NaN or a list.
+fn invalid_key(message: String) -> RuntimeError {
+    RuntimeError::new(ErrorKind::TypeError, message)
+}
+
+impl From<std::io::Error> for RuntimeError {
+    fn from(err: std::io::Error) -> Self {
+        Self::new(ErrorKind::Io, format!("Couldn't write output: {err}."))
+    }
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in &self.trace {
+            write!(f, "\n{frame}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+#[derive(Clone, Debug)]
+pub struct TraceFrame {
+    /// `None` for top-level script code.
+    pub function: Option<Rc<str>>,
+    pub line: usize,
+    /// Source span of the instruction that was executing.
+    pub span: Span,
+}
+
+impl std::fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {name}()", self.line),
+            None => write!(f, "[line {}] in script", self.line),
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! binary_op {
     ($vm:ident, $out:expr, $op:tt) => {
         {
-            use $crate::value::Value;
-            if !matches!($vm.peek(0), Value::Number(_)) || !matches!($vm.peek(1), Value::Number(_)) {
-                return Err("Operands must be numbers.".to_owned());
+            use $crate::value::ValueKind;
+            if !$vm.peek(0).is_number() || !$vm.peek(1).is_number() {
+                return Err($crate::vm::RuntimeError::new(
+                    $crate::vm::ErrorKind::TypeError,
+                    "Operands must be numbers.",
+                ));
             }
             let maybe_b = $vm.pop();
             let maybe_a = $vm.pop();
-            let Value::Number(b) = maybe_b else {
+            let ValueKind::Number(b) = maybe_b.kind() else {
                 panic!("previously checked value of $vm.pop was invalid (not number, {maybe_b:?})");
             };
-            let Value::Number(a) = maybe_a else {
+            let ValueKind::Number(a) = maybe_a.kind() else {
                 panic!("previously checked value of $vm.pop was invalid (not number, {maybe_a:?})");
             };
             $vm.push($out(a $op b));
     }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CYCLES: &str = r#"
+        class Node {}
+        fun make() {
+            var node = Node();
+            fun get() { return node; }
+            node.getter = get;
+            node.next = Node();
+            node.next.prev = node;
+        }
+        for (var i = 0; i < 100; i = i + 1) make();
+    "#;
+
+    #[test]
+    fn stress_gc_keeps_live_objects() {
+        let mut vm = Vm::init();
+        vm.set_stress_gc(true);
+        let source = r#"
+            fun counter() {
+                var n = 0;
+                fun inc() { n = n + 1; return n; }
+                return inc;
+            }
+            class A { init(x) { this.x = x; } get() { return this.x; } }
+            class B < A { get() { return super.get() + "!"; } }
+            var c = counter();
+            c(); c();
+            var b = B("a" + "b");
+            if (c() != 3) undefinedGlobal;
+            if (b.get() != "ab!") undefinedGlobal;
+            var xs = [b.get() + "?", [str(1)]];
+            push(xs, slice(xs, 0, 1));
+            if (xs[2][0] != "ab!?" or xs[1][0] != "1") undefinedGlobal;
+        "#;
+        assert!(matches!(
+            vm.interpret(source.to_string()),
+            InterpretResult::Ok
+        ));
+    }
+
+    #[test]
+    fn define_native() {
+        fn double(_vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+            match args[0].kind() {
+                ValueKind::Number(n) => Ok(Value::from(n * 2.0)),
+                _ => Err("double() takes a number.".to_string()),
+            }
+        }
+        let mut vm = Vm::init();
+        vm.define_native("double", 1, double);
+        let source = r#"if (double(21) != 42 or type(double) != "function") undefinedGlobal;"#;
+        assert!(matches!(
+            vm.interpret(source.to_string()),
+            InterpretResult::Ok
+        ));
+        assert!(matches!(
+            vm.interpret("double(nil);".to_string()),
+            InterpretResult::RuntimeError(RuntimeError {
+                kind: ErrorKind::Native,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn globals_persist_between_runs() {
+        let mut vm = Vm::init();
+        let source = "fun get() { return later; }";
+        assert!(matches!(
+            vm.interpret(source.to_string()),
+            InterpretResult::Ok
+        ));
+        assert!(matches!(
+            vm.interpret("get();".to_string()),
+            InterpretResult::RuntimeError(RuntimeError {
+                kind: ErrorKind::UndefinedVariable,
+                ..
+            })
+        ));
+        let source = "var later = 1; later = later + 1; if (get() != 2) undefinedGlobal;";
+        assert!(matches!(
+            vm.interpret(source.to_string()),
+            InterpretResult::Ok
+        ));
+        let InterpretResult::RuntimeError(err) = vm.interpret("missing = 1;".to_string()) else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!(err.message, "Undefined variable 'missing'.");
+    }
+
+    #[test]
+    fn runtime_error_trace() {
+        let mut vm = Vm::init();
+        let source = "fun recurse(n) {\n  return recurse(n + 1);\n}\nrecurse(0);";
+        let InterpretResult::RuntimeError(err) = vm.interpret(source.to_string()) else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!(err.kind, ErrorKind::StackOverflow);
+        assert_eq!(err.trace.len(), FRAMES_MAX);
+        assert_eq!(err.trace[0].function.as_deref(), Some("recurse"));
+        assert_eq!(err.trace[0].line, 2);
+        let outermost = err.trace.last().unwrap();
+        assert!(outermost.function.is_none());
+        assert_eq!(outermost.line, 4);
+    }
+
+    #[test]
+    fn runtime_error_span() {
+        let mut vm = Vm::init();
+        let source = "var a = 1;\nprint a + \"b\";";
+        let InterpretResult::RuntimeError(err) = vm.interpret(source.to_string()) else {
+            panic!("expected a runtime error");
+        };
+        let span = err.trace[0].span;
+        assert_eq!(&source[span.start..span.end], "+");
+    }
+
+    #[test]
+    fn collects_cycles() {
+        let mut vm = Vm::init();
+        assert!(matches!(
+            vm.interpret(CYCLES.to_string()),
+            InterpretResult::Ok
+        ));
+        let before = vm.heap().object_count();
+        vm.collect_garbage();
+        let after = vm.heap().object_count();
+        assert!(
+            before > 400,
+            "expected garbage before collecting, had {before}"
+        );
+        // Natives and their names stay alive for the life of the VM.
+        let builtins = Vm::init().heap().object_count();
+        assert!(
+            after < builtins + 10,
+            "cycles survived collection: {after} objects live"
+        );
+    }
+
+    #[test]
+    fn counts_collection_growth() {
+        let mut vm = Vm::init();
+        let source = "
+var list = [];
+var map = {};
+for (var i = 0; i < 1000; i = i + 1) {
+  push(list, i);
+  map[i] = i;
+}
+";
+        assert!(matches!(
+            vm.interpret(source.to_string()),
+            InterpretResult::Ok
+        ));
+        let grown = vm.heap().bytes_allocated();
+        assert!(
+            grown > 3000 * std::mem::size_of::<Value>(),
+            "growth wasn't counted: {grown} bytes"
+        );
+
+        let source = "
+for (var i = 0; i < 1000; i = i + 1) {
+  pop(list);
+  remove(map, i);
+}
+";
+        assert!(matches!(
+            vm.interpret(source.to_string()),
+            InterpretResult::Ok
+        ));
+        let shrunk = vm.heap().bytes_allocated();
+        assert!(
+            shrunk < grown / 2,
+            "shrinking wasn't counted: {shrunk} of {grown} bytes"
+        );
+    }
+
+    struct Closed;
+
+    impl Write for Closed {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn print_reports_write_errors() {
+        let mut vm = Vm::init();
+        vm.set_output(Closed);
+        assert!(matches!(
+            vm.interpret("print 1;".to_string()),
+            InterpretResult::RuntimeError(RuntimeError {
+                kind: ErrorKind::Io,
+                ..
+            })
+        ));
+    }
+
+    #[derive(Clone, Default)]
+    struct Shared(Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Runs `source` on a fresh VM, returning what it printed.
+    fn output(source: &str) -> String {
+        let mut vm = Vm::init();
+        let output = Shared::default();
+        vm.set_output(output.clone());
+        assert!(matches!(
+            vm.interpret(source.to_string()),
+            InterpretResult::Ok
+        ));
+        String::from_utf8(output.0.take()).unwrap()
+    }
+
+    #[test]
+    fn returns_values() {
+        let source = "
+            fun add(a, b) { return a + b; }
+            fun early(x) { if (x) return \"early\"; return \"late\"; }
+            fun nothing() {}
+            print add(1, 2);
+            print early(true) + early(false);
+            print nothing();
+        ";
+        assert_eq!(output(source), "3\nearlylate\nnil\n");
+    }
+
+    #[test]
+    fn checks_arity() {
+        let mut vm = Vm::init();
+        let source = "fun add(a, b) { return a + b; }\nadd(1);";
+        let InterpretResult::RuntimeError(err) = vm.interpret(source.to_string()) else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!(err.kind, ErrorKind::ArityMismatch);
+        assert_eq!(err.message, "Expected 2 arguments but got 1.");
+        assert_eq!(err.trace[0].line, 2);
+    }
+
+    #[test]
+    fn closures_capture_variables() {
+        let source = "
+            fun counter() {
+                var n = 0;
+                fun inc() { n = n + 1; return n; }
+                return inc;
+            }
+            var c = counter();
+            c();
+            print c();
+            print counter()();
+
+            var get;
+            var set;
+            {
+                var shared = \"before\";
+                fun g() { return shared; }
+                fun s(value) { shared = value; }
+                get = g;
+                set = s;
+            }
+            set(\"after\");
+            print get();
+
+            var closures = [];
+            for (var i = 0; i < 2; i = i + 1) {
+                var j = i;
+                fun f() { return j; }
+                push(closures, f);
+            }
+            print closures[0]() + closures[1]();
+        ";
+        // The counter outlives its scope, both closures in the block share
+        // one upvalue closed on `end_scope`, and each iteration gets its own.
+        assert_eq!(output(source), "2\n1\nafter\n1\n");
+    }
+
+    #[test]
+    fn dispatches_through_superclasses() {
+        let source = "
+            class A {
+                name() { return \"A\"; }
+                greet() { return \"hi from \" + this.name(); }
+            }
+            class B < A {
+                name() { return \"B\"; }
+                greet() { return super.greet() + \" via B\"; }
+                parent() {
+                    var method = super.name;
+                    return method();
+                }
+            }
+            class C < B {}
+            var c = C();
+            print c.greet();
+            print c.parent();
+        ";
+        // `super.greet()` runs as `SuperInvoke`, `super.name` as `GetSuper`,
+        // and `C` only has methods through `Inherit`.
+        assert_eq!(output(source), "hi from B via B\nA\n");
+    }
+
+    #[test]
+    fn traces_only_when_asked() {
+        let mut vm = Vm::init();
+        let diagnostics = Shared::default();
+        vm.set_diagnostics(diagnostics.clone());
+        vm.set_output(std::io::sink());
+        assert!(matches!(
+            vm.interpret("print 1;".to_string()),
+            InterpretResult::Ok
+        ));
+        assert!(diagnostics.0.borrow().is_empty());
+
+        vm.set_trace(TraceOptions {
+            code: true,
+            disassemble: true,
+            stack: true,
+        });
+        assert!(matches!(
+            vm.interpret("print 1;".to_string()),
+            InterpretResult::Ok
+        ));
+        let trace = String::from_utf8(diagnostics.0.take()).unwrap();
+        assert!(trace.starts_with("=== <script> ==="), "{trace}");
+        assert!(trace.contains("0002 0001 Op::Print"), "{trace}");
+        assert!(trace.contains("[ <script> ][ 1 ]"), "{trace}");
+    }
+
+    #[test]
+    fn logs_gc_to_diagnostics() {
+        let mut vm = Vm::init();
+        vm.set_log_gc(true);
+        let diagnostics = Shared::default();
+        vm.set_diagnostics(diagnostics.clone());
+        vm.set_stress_gc(true);
+        assert!(matches!(
+            vm.interpret("var a = \"a\" + \"b\";".to_string()),
+            InterpretResult::Ok
+        ));
+        let log = String::from_utf8(diagnostics.0.take()).unwrap();
+        assert!(log.contains("-- gc begin"), "{log}");
+        assert!(log.contains("allocate"), "{log}");
+    }
+}
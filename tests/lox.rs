@@ -0,0 +1,158 @@
+//! Runs the Lox conformance suite in `tests/lox`, ported from Crafting
+//! Interpreters.
+//!
+//! Each script declares what it should do in comments:
+//!
+//! - `// expect: output` is a line the script prints.
+//! - `// expect runtime error: message` is the error it stops with, raised
+//!   on that line.
+//! - `// Error at 'token': message` is a compile error reported on that line.
+//! - `// [line N] Error at 'token': message` is a compile error reported on
+//!   line `N`, for errors that can't share a line with their comment.
+//!   `[c line N]` is accepted the same way, while `[java line N]` errors only
+//!   apply to jlox and are ignored.
+//!
+//! Compile errors are checked against the `Display` form of the compiler's
+//! diagnostics, runtime errors against their message and innermost line.
+//!
+//! A few official tests are left out on purpose:
+//!
+//! - `limit/too_many_constants` and `limit/no_reuse_constants` expect an error
+//!   once a chunk has more than 256 constants, but rlox's long instructions
+//!   address up to 2^24.
+//! - `scanning` and `expressions` only apply to jlox's early chapters, which
+//!   the clox suite skips as well.
+//! - `benchmark` holds timing programs with no expectations to check.
+
+use std::{
+    cell::RefCell,
+    io::Write,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use rlox::vm::{InterpretResult, Vm};
+
+#[derive(Default)]
+struct Expectations {
+    output: Vec<String>,
+    compile_errors: Vec<String>,
+    runtime_error: Option<(String, usize)>,
+}
+
+impl Expectations {
+    fn parse(source: &str) -> Self {
+        let mut expected = Self::default();
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index + 1;
+            if let Some((_, output)) = line.split_once("// expect: ") {
+                expected.output.push(output.to_string());
+            } else if let Some((_, message)) = line.split_once("// expect runtime error: ") {
+                expected.runtime_error = Some((message.to_string(), line_number));
+            } else if let Some((_, error)) = line.split_once("// Error") {
+                expected
+                    .compile_errors
+                    .push(format!("[line {line_number}] Error{error}"));
+            } else if let Some((_, error)) = line.split_once("// [line ") {
+                expected.compile_errors.push(format!("[line {error}"));
+            } else if let Some((_, error)) = line.split_once("// [c line ") {
+                expected.compile_errors.push(format!("[line {error}"));
+            }
+        }
+        expected
+    }
+}
+
+fn scripts(dir: &Path, found: &mut Vec<PathBuf>) {
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            scripts(&path, found);
+        } else if path.extension().is_some_and(|ext| ext == "lox") {
+            found.push(path);
+        }
+    }
+}
+
+/// Collects what the VM writes, so it can be read back after a run.
+#[derive(Clone, Default)]
+struct Captured(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Checks one script, describing every way it failed to meet its expectations.
+fn check(path: &Path) -> Vec<String> {
+    let source = std::fs::read_to_string(path).unwrap();
+    let expected = Expectations::parse(&source);
+    let mut failures = Vec::new();
+
+    let output = Captured::default();
+    let mut vm = Vm::init();
+    vm.set_output(output.clone());
+    let result = vm.interpret(source);
+
+    let output = String::from_utf8(output.0.take()).unwrap();
+    let output: Vec<&str> = output.lines().collect();
+    if output != expected.output {
+        failures.push(format!(
+            "expected output {:#?}, got {output:#?}",
+            expected.output
+        ));
+    }
+
+    let diagnostics = match &result {
+        InterpretResult::CompileError(diagnostics) => {
+            diagnostics.iter().map(ToString::to_string).collect()
+        }
+        _ => Vec::new(),
+    };
+    if diagnostics != expected.compile_errors {
+        failures.push(format!(
+            "expected compile errors {:#?}, got {diagnostics:#?}",
+            expected.compile_errors
+        ));
+    }
+
+    let runtime_error = match &result {
+        InterpretResult::RuntimeError(err) => Some((err.message.clone(), err.trace[0].line)),
+        _ => None,
+    };
+    if runtime_error != expected.runtime_error {
+        failures.push(format!(
+            "expected runtime error {:?}, got {runtime_error:?}",
+            expected.runtime_error
+        ));
+    }
+    failures
+}
+
+#[test]
+fn conformance() {
+    let mut paths = Vec::new();
+    scripts(
+        &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox"),
+        &mut paths,
+    );
+    paths.sort();
+    assert!(!paths.is_empty(), "no scripts in tests/lox");
+
+    let mut failed = 0;
+    for path in &paths {
+        let failures = check(path);
+        if !failures.is_empty() {
+            failed += 1;
+            eprintln!("{}:", path.display());
+            for failure in failures {
+                eprintln!("  {failure}");
+            }
+        }
+    }
+    assert_eq!(failed, 0, "{failed} of {} scripts failed", paths.len());
+}